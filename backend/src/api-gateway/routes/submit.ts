@@ -22,56 +22,49 @@ const claimSchema = z.object({
 });
 
 interface WithdrawalEntry {
+  id: bigint;
   user: string;
   claimed: boolean;
-  xlm_amount: string;
+  xlm_amount: bigint;
   unlock_ledger: number;
 }
 
 /**
- * Scan the staking contract's withdrawal queue to find the contract-side
- * withdrawal ID for a given user address. The contract uses a global 0-based
- * counter while the DB uses 1-based auto-increment, so they diverge for
- * multi-user scenarios. Returns { id, entry } or null if not found.
+ * Look up the user's oldest open withdrawal through the staking contract's
+ * per-user index. The contract uses a global 0-based counter while the DB
+ * uses 1-based auto-increment, so the IDs diverge for multi-user scenarios,
+ * and claimed requests are pruned, so IDs cannot be scanned from 0 either.
+ * Returns { id, entry } or null if the user has no open withdrawal.
  */
 async function findContractWithdrawal(
   server: rpc.Server,
   userAddress: string,
-  maxScan = 50,
 ): Promise<{ id: number; entry: WithdrawalEntry } | null> {
   const stakingContract = new Contract(config.contracts.stakingContractId);
   const adminAccount = await server.getAccount(config.admin.publicKey);
 
-  for (let id = 0; id < maxScan; id++) {
-    try {
-      const tx = new TransactionBuilder(adminAccount, {
-        fee: BASE_FEE,
-        networkPassphrase: config.stellar.networkPassphrase,
-      })
-        .addOperation(
-          stakingContract.call(
-            "get_withdrawal",
-            nativeToScVal(id, { type: "u64" })
-          )
-        )
-        .setTimeout(30)
-        .build();
-
-      const sim = await server.simulateTransaction(tx);
-
-      if (!rpc.Api.isSimulationSuccess(sim) || !sim.result) break; // past end of queue
-
-      const entry = scValToNative(sim.result.retval) as WithdrawalEntry;
-
-      if (entry.user === userAddress && !entry.claimed) {
-        return { id, entry };
-      }
-    } catch {
-      break; // no more entries
-    }
-  }
-
-  return null;
+  const tx = new TransactionBuilder(adminAccount, {
+    fee: BASE_FEE,
+    networkPassphrase: config.stellar.networkPassphrase,
+  })
+    .addOperation(
+      stakingContract.call(
+        "get_user_withdrawals",
+        new Address(userAddress).toScVal(),
+        nativeToScVal(0, { type: "u32" }),
+        nativeToScVal(1, { type: "u32" })
+      )
+    )
+    .setTimeout(30)
+    .build();
+
+  const sim = await server.simulateTransaction(tx);
+  if (!rpc.Api.isSimulationSuccess(sim) || !sim.result) return null;
+
+  const [entry] = scValToNative(sim.result.retval) as WithdrawalEntry[];
+  if (!entry) return null;
+
+  return { id: Number(entry.id), entry };
 }
 
 export const submitRoutes: FastifyPluginAsync<{ stakingEngine: StakingEngine }> = async (
@@ -146,14 +139,14 @@ export const submitRoutes: FastifyPluginAsync<{ stakingEngine: StakingEngine }>
    *
    * The contract uses a 0-based auto-increment counter for withdrawal IDs,
    * but the frontend DB uses 1-based auto-increment IDs. To avoid mismatches,
-   * we scan the contract's withdrawal queue to find the correct contract-side
-   * ID for this user rather than blindly trusting the DB-provided withdrawalId.
+   * we ask the contract for this user's open withdrawals to find the correct
+   * contract-side ID rather than blindly trusting the DB-provided withdrawalId.
    */
   fastify.post("/staking/claim", async (request, reply) => {
     try {
       const body = claimSchema.parse(request.body);
 
-      // Find the correct contract withdrawal ID by scanning the queue.
+      // Find the correct contract withdrawal ID from the user's open requests.
       // Contract IDs start at 0 and auto-increment globally (not per-user).
       const withdrawal = await findContractWithdrawal(server, body.userAddress);
       if (withdrawal === null) {
@@ -0,0 +1,132 @@
+//! Two-step admin handover: the current admin proposes a successor, who takes
+//! over only by calling back in with their own signature.
+
+use soroban_sdk::{contracttype, symbol_short, Address, Env};
+
+#[derive(Clone)]
+#[contracttype]
+enum AdminKey {
+    PendingAdmin,
+}
+
+/// Record `new_admin` as the proposed successor to `admin`, replacing any
+/// earlier proposal. The caller checks `admin`'s auth.
+pub fn propose(env: &Env, admin: Address, new_admin: Address) {
+    env.storage()
+        .instance()
+        .set(&AdminKey::PendingAdmin, &new_admin);
+    env.events()
+        .publish((symbol_short!("adm_prop"),), (admin, new_admin));
+}
+
+/// Require the proposed admin's auth, clear the proposal and return the new
+/// admin, which the caller stores in place of `previous`.
+pub fn accept(env: &Env, previous: Address) -> Address {
+    let new_admin = pending(env).expect("no pending admin");
+    new_admin.require_auth();
+    env.storage().instance().remove(&AdminKey::PendingAdmin);
+    env.events()
+        .publish((symbol_short!("adm_acc"),), (previous, new_admin.clone()));
+    new_admin
+}
+
+/// Drop the pending proposal. The caller checks the admin's auth.
+pub fn cancel(env: &Env) {
+    let pending = pending(env).expect("no pending admin");
+    env.storage().instance().remove(&AdminKey::PendingAdmin);
+    env.events().publish((symbol_short!("adm_cncl"),), pending);
+}
+
+pub fn pending(env: &Env) -> Option<Address> {
+    env.storage().instance().get(&AdminKey::PendingAdmin)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soroban_sdk::testutils::Address as _;
+    use soroban_sdk::{contract, contractimpl, symbol_short};
+
+    #[contract]
+    struct Harness;
+
+    #[contractimpl]
+    impl Harness {
+        pub fn init(env: Env, admin: Address) {
+            env.storage()
+                .instance()
+                .set(&symbol_short!("admin"), &admin);
+        }
+
+        pub fn admin(env: Env) -> Address {
+            env.storage()
+                .instance()
+                .get(&symbol_short!("admin"))
+                .unwrap()
+        }
+
+        pub fn propose(env: Env, new_admin: Address) {
+            let admin = Self::admin(env.clone());
+            admin.require_auth();
+            propose(&env, admin, new_admin);
+        }
+
+        pub fn accept(env: Env) {
+            let new_admin = accept(&env, Self::admin(env.clone()));
+            env.storage()
+                .instance()
+                .set(&symbol_short!("admin"), &new_admin);
+        }
+
+        pub fn cancel(env: Env) {
+            Self::admin(env.clone()).require_auth();
+            cancel(&env);
+        }
+
+        pub fn pending(env: Env) -> Option<Address> {
+            pending(&env)
+        }
+    }
+
+    #[test]
+    fn test_handover_needs_the_new_admin() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let client = HarnessClient::new(&env, &env.register_contract(None, Harness));
+        let admin = Address::generate(&env);
+        let new_admin = Address::generate(&env);
+        client.init(&admin);
+        assert!(client.try_accept().is_err());
+
+        client.propose(&new_admin);
+        assert_eq!(client.admin(), admin);
+        assert_eq!(client.pending(), Some(new_admin.clone()));
+
+        client.accept();
+        assert_eq!(env.auths()[0].0, new_admin);
+        assert_eq!(client.admin(), new_admin);
+        assert_eq!(client.pending(), None);
+        assert!(client.try_accept().is_err());
+    }
+
+    #[test]
+    fn test_later_proposal_replaces_and_cancel_clears() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let client = HarnessClient::new(&env, &env.register_contract(None, Harness));
+        let admin = Address::generate(&env);
+        let first = Address::generate(&env);
+        let second = Address::generate(&env);
+        client.init(&admin);
+
+        client.propose(&first);
+        client.propose(&second);
+        assert_eq!(client.pending(), Some(second));
+
+        client.cancel();
+        assert_eq!(client.pending(), None);
+        assert!(client.try_cancel().is_err());
+        assert!(client.try_accept().is_err());
+        assert_eq!(client.admin(), admin);
+    }
+}
@@ -0,0 +1,10 @@
+//! Admin plumbing shared by the sXLM contracts.
+//!
+//! Each contract keeps its own `Admin` entry and decides who may call what;
+//! the helpers here only manage the extra state around it: a proposed
+//! successor admin and a scheduled upgrade. They use instance storage keys
+//! that do not appear in any contract's `DataKey`.
+#![no_std]
+
+pub mod handover;
+pub mod upgrade;
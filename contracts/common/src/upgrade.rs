@@ -0,0 +1,130 @@
+//! Timelocked WASM upgrades: an upgrade is announced with an eta at least
+//! `MIN_UPGRADE_DELAY` ledgers out, and anyone may install it from then on.
+
+use soroban_sdk::{contracttype, symbol_short, BytesN, Env};
+
+/// Minimum number of ledgers between scheduling an upgrade and executing it
+/// (~24 hours), giving users time to exit first.
+pub const MIN_UPGRADE_DELAY: u32 = 17_280;
+
+/// WASM upgrade announced by `schedule`.
+#[derive(Clone)]
+#[contracttype]
+pub struct PendingUpgrade {
+    pub wasm_hash: BytesN<32>,
+    /// First ledger at which the upgrade may be installed.
+    pub eta: u32,
+}
+
+#[derive(Clone)]
+#[contracttype]
+enum UpgradeKey {
+    PendingUpgrade,
+}
+
+/// Announce an upgrade to `wasm_hash` from `eta_ledger` on, replacing any
+/// upgrade already scheduled. The caller checks auth.
+pub fn schedule(env: &Env, wasm_hash: BytesN<32>, eta_ledger: u32) {
+    if eta_ledger < env.ledger().sequence() + MIN_UPGRADE_DELAY {
+        panic!("upgrade delay too short");
+    }
+    env.storage().instance().set(
+        &UpgradeKey::PendingUpgrade,
+        &PendingUpgrade {
+            wasm_hash: wasm_hash.clone(),
+            eta: eta_ledger,
+        },
+    );
+    env.events()
+        .publish((symbol_short!("upg_sched"),), (wasm_hash, eta_ledger));
+}
+
+/// Install the scheduled upgrade once its eta has passed.
+pub fn execute(env: &Env) {
+    let pending = pending(env).expect("no pending upgrade");
+    if env.ledger().sequence() < pending.eta {
+        panic!("upgrade timelock not expired");
+    }
+    env.storage().instance().remove(&UpgradeKey::PendingUpgrade);
+    env.events()
+        .publish((symbol_short!("upgraded"),), pending.wasm_hash.clone());
+    env.deployer()
+        .update_current_contract_wasm(pending.wasm_hash);
+}
+
+/// Drop the scheduled upgrade. The caller checks auth.
+pub fn cancel(env: &Env) {
+    let pending = pending(env).expect("no pending upgrade");
+    env.storage().instance().remove(&UpgradeKey::PendingUpgrade);
+    env.events()
+        .publish((symbol_short!("upg_cncl"),), pending.wasm_hash);
+}
+
+pub fn pending(env: &Env) -> Option<PendingUpgrade> {
+    env.storage().instance().get(&UpgradeKey::PendingUpgrade)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soroban_sdk::{contract, contractimpl};
+
+    #[contract]
+    struct Harness;
+
+    #[contractimpl]
+    impl Harness {
+        pub fn schedule(env: Env, wasm_hash: BytesN<32>, eta_ledger: u32) {
+            schedule(&env, wasm_hash, eta_ledger);
+        }
+
+        pub fn execute(env: Env) {
+            execute(&env);
+        }
+
+        pub fn cancel(env: Env) {
+            cancel(&env);
+        }
+
+        pub fn pending(env: Env) -> Option<PendingUpgrade> {
+            pending(&env)
+        }
+    }
+
+    #[test]
+    fn test_schedule_enforces_the_delay() {
+        let env = Env::default();
+        let client = HarnessClient::new(&env, &env.register_contract(None, Harness));
+        let hash = BytesN::from_array(&env, &[7; 32]);
+        let now = env.ledger().sequence();
+
+        assert!(client
+            .try_schedule(&hash, &(now + MIN_UPGRADE_DELAY - 1))
+            .is_err());
+        client.schedule(&hash, &(now + MIN_UPGRADE_DELAY));
+        assert_eq!(client.pending().unwrap().wasm_hash, hash);
+
+        assert!(client.try_execute().is_err());
+        assert!(client.pending().is_some());
+    }
+
+    #[test]
+    fn test_reschedule_replaces_and_cancel_clears() {
+        let env = Env::default();
+        let client = HarnessClient::new(&env, &env.register_contract(None, Harness));
+        let first = BytesN::from_array(&env, &[7; 32]);
+        let second = BytesN::from_array(&env, &[8; 32]);
+        let eta = env.ledger().sequence() + MIN_UPGRADE_DELAY;
+
+        client.schedule(&first, &eta);
+        client.schedule(&second, &(eta + 10));
+        let pending = client.pending().unwrap();
+        assert_eq!(pending.wasm_hash, second);
+        assert_eq!(pending.eta, eta + 10);
+
+        client.cancel();
+        assert!(client.pending().is_none());
+        assert!(client.try_cancel().is_err());
+        assert!(client.try_execute().is_err());
+    }
+}
@@ -1,6 +1,10 @@
 #![no_std]
 
-use soroban_sdk::{contract, contractimpl, contracttype, token, Address, BytesN, Env, String};
+use soroban_sdk::{
+    contract, contractclient, contractimpl, contracttype, token, Address, BytesN, Env, String,
+};
+use sxlm_common::handover;
+use sxlm_common::upgrade::{self, PendingUpgrade};
 
 const BPS_DENOMINATOR: i128 = 10_000;
 const MIN_PROPOSAL_BALANCE: i128 = 100_0000000; // 100 sXLM minimum to create proposal
@@ -11,10 +15,15 @@ const INSTANCE_BUMP_AMOUNT: u32 = 518_400;        // bump to ~30 days
 const PROPOSAL_LIFETIME_THRESHOLD: u32 = 518_400; // ~30 days
 const PROPOSAL_BUMP_AMOUNT: u32 = 3_110_400;      // bump to ~180 days
 
+/// Storage layout written by `initialize` and reached by `migrate`.
+/// Deployments from before layouts were versioned read as version 0.
+const SCHEMA_VERSION: u32 = 1;
+
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
     Admin,
+    SchemaVersion,
     SxlmToken,
     VotingPeriodLedgers,
     QuorumBps,
@@ -26,6 +35,22 @@ pub enum DataKey {
     Param(String),
     // Total sXLM supply reference for quorum calculation (set by admin)
     ReferenceSupply,
+    // Contract upgrade scheduled when an upgrade proposal executes
+    UpgradeAction(u64),
+}
+
+/// Upgrade carried by a proposal from `propose_upgrade`.
+#[derive(Clone)]
+#[contracttype]
+pub struct UpgradeAction {
+    pub target: Address,
+    pub wasm_hash: BytesN<32>,
+}
+
+/// Scheduling entry point shared by every contract in the protocol.
+#[contractclient(name = "UpgradeableClient")]
+pub trait Upgradeable {
+    fn schedule_upgrade(env: Env, new_wasm_hash: BytesN<32>, eta_ledger: u32);
 }
 
 #[derive(Clone)]
@@ -68,6 +93,13 @@ fn extend_vote(env: &Env, proposal_id: u64, voter: &Address) {
     }
 }
 
+fn read_schema_version(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::SchemaVersion)
+        .unwrap_or(0)
+}
+
 fn read_admin(env: &Env) -> Address {
     env.storage().instance().get(&DataKey::Admin).unwrap()
 }
@@ -139,6 +171,44 @@ fn set_voted(env: &Env, proposal_id: u64, voter: &Address) {
         .extend_ttl(&key, PROPOSAL_LIFETIME_THRESHOLD, PROPOSAL_BUMP_AMOUNT);
 }
 
+fn open_proposal(env: &Env, proposer: &Address, param_key: String, new_value: String) -> u64 {
+    proposer.require_auth();
+    extend_instance(env);
+
+    // Check minimum sXLM balance
+    let sxlm = read_sxlm_token(env);
+    let balance = token::Client::new(env, &sxlm).balance(proposer);
+    assert!(
+        balance >= MIN_PROPOSAL_BALANCE,
+        "insufficient sXLM to create proposal"
+    );
+
+    let id = next_proposal_id(env);
+    let current_ledger = env.ledger().sequence();
+    let voting_period = read_voting_period(env);
+
+    let proposal = Proposal {
+        id,
+        proposer: proposer.clone(),
+        param_key: param_key.clone(),
+        new_value,
+        votes_for: 0,
+        votes_against: 0,
+        start_ledger: current_ledger,
+        end_ledger: current_ledger + voting_period,
+        executed: false,
+    };
+
+    write_proposal(env, &proposal);
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("propose"),),
+        (id, proposer.clone(), param_key),
+    );
+
+    id
+}
+
 #[contract]
 pub struct GovernanceContract;
 
@@ -163,14 +233,65 @@ impl GovernanceContract {
         env.storage().instance().set(&DataKey::QuorumBps, &(quorum_bps as i128));
         // Default reference supply: 0 means quorum check uses absolute minimum
         env.storage().instance().set(&DataKey::ReferenceSupply, &0i128);
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        extend_instance(&env);
+    }
+
+    /// Schedule an upgrade of this contract without a vote. Passed upgrade
+    /// proposals targeting this contract land in the same slot.
+    pub fn schedule_upgrade(env: Env, new_wasm_hash: BytesN<32>, eta_ledger: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
         extend_instance(&env);
+        upgrade::schedule(&env, new_wasm_hash, eta_ledger);
     }
 
-    /// Upgrade the contract WASM. Only callable by admin.
-    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>) {
+    /// Install the scheduled upgrade once its eta has passed. Callable by anyone.
+    pub fn execute_upgrade(env: Env) {
+        upgrade::execute(&env);
+    }
+
+    pub fn cancel_upgrade(env: Env) {
         let admin = read_admin(&env);
         admin.require_auth();
-        env.deployer().update_current_contract_wasm(new_wasm_hash);
+        extend_instance(&env);
+        upgrade::cancel(&env);
+    }
+
+    pub fn pending_upgrade(env: Env) -> Option<PendingUpgrade> {
+        extend_instance(&env);
+        upgrade::pending(&env)
+    }
+
+    /// Bring storage written by an older WASM up to `SCHEMA_VERSION`.
+    /// `from_version` must match `version()`, so a migration cannot be applied
+    /// twice or out of order. Returns the version reached.
+    pub fn migrate(env: Env, from_version: u32) -> u32 {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        let version = read_schema_version(&env);
+        assert!(from_version == version, "schema version mismatch");
+        assert!(version < SCHEMA_VERSION, "already at latest schema version");
+
+        // `Proposal` entries and votes written before versioning already
+        // match version 1. Later changes to their fields convert them here.
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("migrated"),),
+            (from_version, SCHEMA_VERSION),
+        );
+        SCHEMA_VERSION
+    }
+
+    /// Storage layout version of this deployment.
+    pub fn version(env: Env) -> u32 {
+        extend_instance(&env);
+        read_schema_version(&env)
     }
 
     /// Bump instance TTL — can be called by anyone to keep contract alive.
@@ -178,6 +299,40 @@ impl GovernanceContract {
         extend_instance(&env);
     }
 
+    /// Propose `new_admin` as the governance admin, who sets the quorum
+    /// reference supply and can schedule upgrades without a vote. Takes effect
+    /// once they call `accept_admin`.
+    pub fn propose_admin(env: Env, new_admin: Address) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::propose(&env, admin, new_admin);
+    }
+
+    /// Complete the handover. Only the proposed admin can call this.
+    pub fn accept_admin(env: Env) {
+        extend_instance(&env);
+        let new_admin = handover::accept(&env, read_admin(&env));
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+    }
+
+    pub fn cancel_admin_transfer(env: Env) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::cancel(&env);
+    }
+
+    pub fn pending_admin(env: Env) -> Option<Address> {
+        extend_instance(&env);
+        handover::pending(&env)
+    }
+
+    pub fn admin(env: Env) -> Address {
+        extend_instance(&env);
+        read_admin(&env)
+    }
+
     /// Set the reference total supply for quorum calculation. Only callable by admin.
     pub fn set_reference_supply(env: Env, supply: i128) {
         let admin = read_admin(&env);
@@ -194,38 +349,40 @@ impl GovernanceContract {
         param_key: String,
         new_value: String,
     ) -> u64 {
-        proposer.require_auth();
-        extend_instance(&env);
+        open_proposal(&env, &proposer, param_key, new_value)
+    }
 
-        // Check minimum sXLM balance
-        let sxlm = read_sxlm_token(&env);
-        let balance = token::Client::new(&env, &sxlm).balance(&proposer);
-        assert!(
-            balance >= MIN_PROPOSAL_BALANCE,
-            "insufficient sXLM to create proposal"
+    /// Propose upgrading `target` to `new_wasm_hash`. If the proposal passes,
+    /// executing it schedules the upgrade on `target`
+    /// `upgrade::MIN_UPGRADE_DELAY` ledgers out, so this contract must be
+    /// `target`'s admin (or upgrader). `target` may be this contract.
+    pub fn propose_upgrade(
+        env: Env,
+        proposer: Address,
+        target: Address,
+        new_wasm_hash: BytesN<32>,
+    ) -> u64 {
+        let id = open_proposal(
+            &env,
+            &proposer,
+            String::from_str(&env, "upgrade"),
+            String::from_str(&env, ""),
         );
-
-        let id = next_proposal_id(&env);
-        let current_ledger = env.ledger().sequence();
-        let voting_period = read_voting_period(&env);
-
-        let proposal = Proposal {
-            id,
-            proposer: proposer.clone(),
-            param_key: param_key.clone(),
-            new_value: new_value.clone(),
-            votes_for: 0,
-            votes_against: 0,
-            start_ledger: current_ledger,
-            end_ledger: current_ledger + voting_period,
-            executed: false,
-        };
-
-        write_proposal(&env, &proposal);
+        let key = DataKey::UpgradeAction(id);
+        env.storage().persistent().set(
+            &key,
+            &UpgradeAction {
+                target: target.clone(),
+                wasm_hash: new_wasm_hash.clone(),
+            },
+        );
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PROPOSAL_LIFETIME_THRESHOLD, PROPOSAL_BUMP_AMOUNT);
 
         env.events().publish(
-            (soroban_sdk::symbol_short!("propose"),),
-            (id, proposer, param_key),
+            (soroban_sdk::symbol_short!("prop_upg"),),
+            (id, target, new_wasm_hash),
         );
 
         id
@@ -308,15 +465,30 @@ impl GovernanceContract {
             "proposal did not pass"
         );
 
-        // Store the approved parameter value on-chain
-        let param_key = DataKey::Param(proposal.param_key.clone());
-        env.storage().persistent().set(
-            &param_key,
-            &proposal.new_value,
-        );
-        env.storage()
+        let action: Option<UpgradeAction> = env
+            .storage()
             .persistent()
-            .extend_ttl(&param_key, PROPOSAL_LIFETIME_THRESHOLD, PROPOSAL_BUMP_AMOUNT);
+            .get(&DataKey::UpgradeAction(proposal_id));
+        if let Some(action) = action {
+            // Schedule the approved upgrade; the target's own timelock applies
+            let eta = current_ledger + upgrade::MIN_UPGRADE_DELAY;
+            if action.target == env.current_contract_address() {
+                upgrade::schedule(&env, action.wasm_hash, eta);
+            } else {
+                UpgradeableClient::new(&env, &action.target)
+                    .schedule_upgrade(&action.wasm_hash, &eta);
+            }
+        } else {
+            // Store the approved parameter value on-chain
+            let param_key = DataKey::Param(proposal.param_key.clone());
+            env.storage().persistent().set(
+                &param_key,
+                &proposal.new_value,
+            );
+            env.storage()
+                .persistent()
+                .extend_ttl(&param_key, PROPOSAL_LIFETIME_THRESHOLD, PROPOSAL_BUMP_AMOUNT);
+        }
 
         proposal.executed = true;
         write_proposal(&env, &proposal);
@@ -348,6 +520,12 @@ impl GovernanceContract {
         (proposal.votes_for, proposal.votes_against)
     }
 
+    /// Upgrade a proposal schedules when executed, if it is an upgrade proposal.
+    pub fn get_upgrade_action(env: Env, id: u64) -> Option<UpgradeAction> {
+        extend_instance(&env);
+        env.storage().persistent().get(&DataKey::UpgradeAction(id))
+    }
+
     /// Read an approved governance parameter value.
     pub fn get_param(env: Env, key: String) -> String {
         extend_instance(&env);
@@ -370,7 +548,26 @@ impl GovernanceContract {
 mod test {
     use super::*;
     use soroban_sdk::testutils::{Address as _, Ledger};
-    use soroban_sdk::{token::StellarAssetClient, Env, String};
+    use soroban_sdk::{
+        contract, contractimpl, symbol_short, token::StellarAssetClient, Env, String,
+    };
+
+    /// Stand-in for a protocol contract whose admin is the governance contract.
+    #[contract]
+    struct MockUpgradeable;
+
+    #[contractimpl]
+    impl MockUpgradeable {
+        pub fn schedule_upgrade(env: Env, new_wasm_hash: BytesN<32>, eta_ledger: u32) {
+            env.storage()
+                .instance()
+                .set(&symbol_short!("upgrade"), &(new_wasm_hash, eta_ledger));
+        }
+
+        pub fn scheduled(env: Env) -> (BytesN<32>, u32) {
+            env.storage().instance().get(&symbol_short!("upgrade")).unwrap()
+        }
+    }
 
     fn setup_test() -> (Env, Address, Address, Address, Address) {
         let env = Env::default();
@@ -553,4 +750,70 @@ mod test {
         let val = client.get_param(&String::from_str(&env, "nonexistent"));
         assert_eq!(val, String::from_str(&env, ""));
     }
+
+    #[test]
+    fn test_upgrade_proposal_schedules_on_target() {
+        let (env, contract_id, _, proposer, voter) = setup_test();
+        let client = GovernanceContractClient::new(&env, &contract_id);
+        let target = env.register_contract(None, MockUpgradeable);
+        let hash = BytesN::from_array(&env, &[9; 32]);
+
+        let to_target = client.propose_upgrade(&proposer, &target, &hash);
+        let to_self = client.propose_upgrade(&proposer, &contract_id, &hash);
+        assert_eq!(client.get_upgrade_action(&to_target).unwrap().target, target);
+        for id in [to_target, to_self] {
+            client.vote(&proposer, &id, &true);
+            client.vote(&voter, &id, &true);
+        }
+
+        env.ledger().with_mut(|li| {
+            li.sequence_number += 101;
+        });
+        let eta = env.ledger().sequence() + upgrade::MIN_UPGRADE_DELAY;
+
+        client.execute_proposal(&to_target);
+        let scheduled = MockUpgradeableClient::new(&env, &target).scheduled();
+        assert_eq!(scheduled, (hash.clone(), eta));
+
+        client.execute_proposal(&to_self);
+        assert_eq!(client.pending_upgrade().unwrap().eta, eta);
+        assert!(client.try_execute_upgrade().is_err());
+        // The admin can still call off an upgrade a vote scheduled.
+        client.cancel_upgrade();
+        assert!(client.pending_upgrade().is_none());
+        // Upgrade proposals do not write a parameter.
+        let param = client.get_param(&String::from_str(&env, "upgrade"));
+        assert_eq!(param, String::from_str(&env, ""));
+    }
+
+    #[test]
+    fn test_migrate_from_unversioned_layout() {
+        let (env, contract_id, _, proposer, voter) = setup_test();
+        let client = GovernanceContractClient::new(&env, &contract_id);
+        let key = String::from_str(&env, "protocol_fee_bps");
+        let id = client.create_proposal(&proposer, &key, &String::from_str(&env, "500"));
+        client.vote(&proposer, &id, &true);
+        // Storage as written before layouts were versioned.
+        env.as_contract(&client.address, || {
+            env.storage().instance().remove(&DataKey::SchemaVersion);
+        });
+        assert_eq!(client.version(), 0);
+        assert!(client.try_migrate(&1).is_err());
+
+        assert_eq!(client.migrate(&0), SCHEMA_VERSION);
+        assert_eq!(client.version(), SCHEMA_VERSION);
+        assert!(client.try_migrate(&SCHEMA_VERSION).is_err());
+
+        // The open proposal and its votes carry over.
+        let proposal = client.get_proposal(&id);
+        assert_eq!(proposal.proposer, proposer);
+        assert_eq!(proposal.votes_for, 100_000_000_000);
+        assert!(client.try_vote(&proposer, &id, &true).is_err());
+        client.vote(&voter, &id, &false);
+        env.ledger().with_mut(|li| {
+            li.sequence_number += 101;
+        });
+        client.execute_proposal(&id);
+        assert_eq!(client.get_param(&key), String::from_str(&env, "500"));
+    }
 }
@@ -1,6 +1,8 @@
 #![no_std]
 
 use soroban_sdk::{contract, contractimpl, contracttype, token, Address, BytesN, Env};
+use sxlm_common::handover;
+use sxlm_common::upgrade::{self, PendingUpgrade};
 
 const BPS_DENOMINATOR: i128 = 10_000;
 const RATE_PRECISION: i128 = 10_000_000; // 1e7
@@ -15,10 +17,15 @@ const INSTANCE_BUMP_AMOUNT: u32 = 518_400;        // bump to ~30 days
 const USER_LIFETIME_THRESHOLD: u32 = 518_400;     // ~30 days
 const USER_BUMP_AMOUNT: u32 = 3_110_400;          // bump to ~180 days
 
+/// Storage layout written by `initialize` and reached by `migrate`.
+/// Deployments from before layouts were versioned read as version 0.
+const SCHEMA_VERSION: u32 = 1;
+
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
     Admin,
+    SchemaVersion,
     SxlmToken,
     NativeToken,
     CollateralFactorBps,
@@ -64,6 +71,13 @@ fn write_i128(env: &Env, key: &DataKey, val: i128) {
     env.storage().instance().set(key, &val);
 }
 
+fn read_schema_version(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::SchemaVersion)
+        .unwrap_or(0)
+}
+
 fn read_admin(env: &Env) -> Address {
     env.storage().instance().get(&DataKey::Admin).unwrap()
 }
@@ -181,14 +195,70 @@ impl LendingContract {
         env.storage().instance().set(&DataKey::BorrowRateBps, &(borrow_rate_bps as i128));
         env.storage().instance().set(&DataKey::LiquidationBonusBps, &DEFAULT_LIQUIDATION_BONUS_BPS);
         env.storage().instance().set(&DataKey::ExchangeRate, &RATE_PRECISION); // 1:1 initial
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        extend_instance(&env);
+    }
+
+    /// Announce a new lending WASM, installable from `eta_ledger`. Borrowers
+    /// get at least `upgrade::MIN_UPGRADE_DELAY` ledgers to repay and withdraw
+    /// first.
+    pub fn schedule_upgrade(env: Env, new_wasm_hash: BytesN<32>, eta_ledger: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
         extend_instance(&env);
+        upgrade::schedule(&env, new_wasm_hash, eta_ledger);
     }
 
-    /// Upgrade the contract WASM. Only callable by admin.
-    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>) {
+    /// Install the scheduled upgrade once its eta has passed. Callable by anyone.
+    pub fn execute_upgrade(env: Env) {
+        upgrade::execute(&env);
+    }
+
+    pub fn cancel_upgrade(env: Env) {
         let admin = read_admin(&env);
         admin.require_auth();
-        env.deployer().update_current_contract_wasm(new_wasm_hash);
+        extend_instance(&env);
+        upgrade::cancel(&env);
+    }
+
+    pub fn pending_upgrade(env: Env) -> Option<PendingUpgrade> {
+        extend_instance(&env);
+        upgrade::pending(&env)
+    }
+
+    /// Bring storage written by an older WASM up to `SCHEMA_VERSION`.
+    /// `from_version` must match `version()`, so a migration cannot be applied
+    /// twice or out of order. Returns the version reached.
+    pub fn migrate(env: Env, from_version: u32) -> u32 {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        let version = read_schema_version(&env);
+        if from_version != version {
+            panic!("schema version mismatch");
+        }
+        if version >= SCHEMA_VERSION {
+            panic!("already at latest schema version");
+        }
+
+        // Positions and pool totals written before versioning already match
+        // version 1.
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("migrated"),),
+            (from_version, SCHEMA_VERSION),
+        );
+        SCHEMA_VERSION
+    }
+
+    /// Storage layout version of this deployment.
+    pub fn version(env: Env) -> u32 {
+        extend_instance(&env);
+        read_schema_version(&env)
     }
 
     /// Bump instance TTL — can be called by anyone to keep contract alive.
@@ -196,6 +266,43 @@ impl LendingContract {
         extend_instance(&env);
     }
 
+    // ==========================================================
+    // Admin handover
+    // ==========================================================
+
+    /// Propose `new_admin` as the pool admin, who sets the sXLM price and the
+    /// risk parameters. Takes effect once they call `accept_admin`.
+    pub fn propose_admin(env: Env, new_admin: Address) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::propose(&env, admin, new_admin);
+    }
+
+    /// Complete the handover. Only the proposed admin can call this.
+    pub fn accept_admin(env: Env) {
+        extend_instance(&env);
+        let new_admin = handover::accept(&env, read_admin(&env));
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+    }
+
+    pub fn cancel_admin_transfer(env: Env) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::cancel(&env);
+    }
+
+    pub fn pending_admin(env: Env) -> Option<Address> {
+        extend_instance(&env);
+        handover::pending(&env)
+    }
+
+    pub fn admin(env: Env) -> Address {
+        extend_instance(&env);
+        read_admin(&env)
+    }
+
     // ==========================================================
     // Admin setters (for governance)
     // ==========================================================
@@ -710,4 +817,45 @@ mod test {
 
         assert_eq!(client.total_borrowed(), 5_000_000_000);
     }
+
+    #[test]
+    fn test_risk_parameters_follow_the_admin() {
+        let (env, contract_id, _, _, _, _, _) = setup_test();
+        let client = LendingContractClient::new(&env, &contract_id);
+        let new_admin = Address::generate(&env);
+
+        client.propose_admin(&new_admin);
+        client.accept_admin();
+        client.update_collateral_factor(&5000);
+        assert_eq!(env.auths()[0].0, new_admin);
+        client.update_exchange_rate(&11_000_000);
+        assert_eq!(env.auths()[0].0, new_admin);
+    }
+
+    #[test]
+    fn test_migrate_from_unversioned_layout() {
+        let (env, contract_id, _, native_id, user, _, _) = setup_test();
+        let client = LendingContractClient::new(&env, &contract_id);
+        client.deposit_collateral(&user, &10_000_000_000);
+        client.borrow(&user, &5_000_000_000);
+        // Storage as written before layouts were versioned.
+        env.as_contract(&client.address, || {
+            env.storage().instance().remove(&DataKey::SchemaVersion);
+        });
+        assert_eq!(client.version(), 0);
+        assert!(client.try_migrate(&1).is_err());
+
+        assert_eq!(client.migrate(&0), SCHEMA_VERSION);
+        assert_eq!(client.version(), SCHEMA_VERSION);
+        assert_eq!(client.get_collateral_factor(), 7000);
+        assert!(client.try_migrate(&SCHEMA_VERSION).is_err());
+
+        // Open positions and pool totals carry over and can still be closed.
+        assert_eq!(client.get_position(&user), (10_000_000_000, 5_000_000_000));
+        assert_eq!(client.total_borrowed(), 5_000_000_000);
+        StellarAssetClient::new(&env, &native_id).mint(&user, &5_000_000_000);
+        client.repay(&user, &5_000_000_000);
+        client.withdraw_collateral(&user, &10_000_000_000);
+        assert_eq!(client.total_collateral(), 0);
+    }
 }
@@ -3,12 +3,23 @@
 use soroban_sdk::{
     contract, contractimpl, contracttype, token, Address, BytesN, Env, Map, Vec,
 };
+use sxlm_common::handover;
+use sxlm_common::upgrade::{self, PendingUpgrade};
 
 /// Precision multiplier for exchange rate calculations (7 decimals).
 const RATE_PRECISION: i128 = 10_000_000; // 1e7
 
-/// Protocol fee in basis points (1000 = 10%).
-const PROTOCOL_FEE_BPS: i128 = 1000;
+/// sXLM held back from the first deposit and kept by the contract for good,
+/// so the supply can never again be small enough for rounding or donations
+/// to move the exchange rate cheaply.
+const LOCKED_SXLM: i128 = 1000;
+
+/// Protocol fee in basis points (1000 = 10%) until the admin sets another.
+const DEFAULT_PROTOCOL_FEE_BPS: i128 = 1000;
+/// Hard cap on the protocol fee (2000 = 20%).
+const MAX_PROTOCOL_FEE_BPS: i128 = 2000;
+/// Maximum number of fee split recipients.
+const MAX_FEE_SPLITS: u32 = 5;
 const BPS_DENOMINATOR: i128 = 10_000;
 
 // ---------- TTL constants ----------
@@ -17,10 +28,32 @@ const INSTANCE_BUMP_AMOUNT: u32        = 518_400;    // bump to ~30 days
 const PERSISTENT_LIFETIME_THRESHOLD: u32 = 518_400; // ~30 days
 const PERSISTENT_BUMP_AMOUNT: u32       = 3_110_400; // bump to ~180 days
 
+/// Maximum number of withdrawal ids read by a single paged query or migration
+/// batch. Each id is its own ledger entry, so this stays well under the
+/// per-transaction read limit.
+const MAX_PAGE_SIZE: u32 = 20;
+
+/// Open requests a user's index may hold before other accounts can no longer
+/// add to it. A user's own requests are never refused.
+const MAX_INBOUND_WITHDRAWALS: u32 = 50;
+
+/// Maximum number of registered validators. Registry-wide operations read one
+/// entry per validator.
+const MAX_VALIDATORS: u32 = 20;
+
+/// Number of exchange-rate checkpoints kept; older ones are dropped.
+const MAX_RATE_CHECKPOINTS: u32 = 64;
+
+/// Storage layout written by `initialize` and reached by `migrate`.
+/// Deployments from before layouts were versioned read as version 0.
+const SCHEMA_VERSION: u32 = 1;
+
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
     Admin,
+    SchemaVersion,
+    OperationPaused(Operation), // bool: one operation paused on its own
     SxlmToken,
     NativeToken,
     TotalXlmStaked,
@@ -28,14 +61,82 @@ pub enum DataKey {
     LiquidityBuffer,
     CooldownPeriod,
     Validators,
-    WithdrawalQueue,
+    WithdrawalQueue, // legacy single-entry Map<u64, WithdrawalRequest>, drained by migrate_withdrawal_queue
     WithdrawalCounter,
     Initialized,
     Paused,
     Treasury,
     TreasuryBalance,
+    UserWithdrawals(Address), // user → Vec<u64> of open withdrawal ids
+    Withdrawal(u64),          // id → WithdrawalRequest, removed once claimed
+    PendingWithdrawalXlm,     // XLM owed to open withdrawal requests
+    QueueHead,                // lowest withdrawal id that may still be open
+    WithdrawalApproval(u64),  // id → Address allowed to transfer the request
+    WithdrawalSxlm(u64),      // id → sXLM burned to open the request; caps cancel_withdrawal
+    RewardVestingPeriod,      // ledgers over which add_rewards is streamed (0 = immediate)
+    UnvestedRewards,          // net rewards not yet counted in TotalXlmStaked
+    RewardVestingEnd,         // ledger at which UnvestedRewards are fully released
+    RewardLastAccrual,        // ledger UnvestedRewards were last released up to
+    ValidatorInfo(Address),   // validator → ValidatorInfo
+    TotalAllocated,           // sum of ValidatorInfo.allocated
+    InsuranceFund,            // XLM set aside to absorb slashing before holders do
+    InsuranceFeeBps,          // share of each protocol fee routed to InsuranceFund
+    ProtocolFeeBps,           // fee taken from add_rewards, capped at MAX_PROTOCOL_FEE_BPS
+    FeeSplits,                // Vec<FeeSplit> paid out of each protocol fee
+    FeeShare(Address),        // recipient → unclaimed fee split balance
+    TotalFeeShares,           // sum of FeeShare balances
+    AutoSweepFees,            // bool: pay the treasury's fee cut out on every add_rewards
+    LiquidityProvided,        // XLM put into the buffer by add_liquidity and still owed to providers
+    RequireFundedRewards,     // bool: only add_rewards_funded may add rewards
+    TargetBufferBps,          // u32: buffer size deposits top up to, in bps of TotalXlmStaked
+    BufferContribution(Address), // XLM a provider has put into the buffer and not yet removed
+    RateHistory,              // Vec<RateCheckpoint>, oldest first, at most MAX_RATE_CHECKPOINTS
+    Insolvent,                // bool: a slash wiped out the staked XLM backing outstanding sXLM
+    RateLimits,               // RateLimits: caps on exchange-rate moves from rewards and slashes
+    RateChangeCounter,        // u64: next pending rate change id
+    PendingRateChange(u64),   // PendingRateChange held back by the rate limits
+    QueuedFundedRewards,      // XLM of funded rewards held back by the rate limits
+    QueuedSlashes,            // u32: slashes held back by the rate limits; deposits and exits wait
+    RoleHolder(Role),         // Address holding a privileged role (admin if unset)
+}
+
+/// User-facing operations that can be paused one at a time.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[contracttype]
+pub enum Operation {
+    Deposit,
+    /// Withdrawals paid from the buffer. While paused, withdrawals are queued.
+    InstantWithdrawal,
+    /// Withdrawals that open a queued request.
+    DelayedWithdrawal,
+    /// Paying out queued requests, including early settlement.
+    Claim,
+    /// Adding rewards, directly or from the rate-change queue.
+    Rewards,
+}
+
+/// Privileged duties that can be handed to keys other than the admin. Each
+/// role has one holder; until one is granted, the admin holds it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[contracttype]
+pub enum Role {
+    /// Adds rewards.
+    Rewarder,
+    /// Applies slashes.
+    Slasher,
+    /// Pauses the protocol or single operations, and vetoes queued rate
+    /// changes. Unpausing is left to the admin.
+    Guardian,
+    /// Maintains the validator registry.
+    ValidatorManager,
+    /// Withdraws fees and manages buffer liquidity.
+    Treasurer,
+    /// Upgrades the contract WASM.
+    Upgrader,
 }
 
+/// A delayed withdrawal. Stored under `DataKey::Withdrawal(id)` until claimed;
+/// `claimed` is only ever set on entries still held in the legacy queue map.
 #[derive(Clone)]
 #[contracttype]
 pub struct WithdrawalRequest {
@@ -46,6 +147,129 @@ pub struct WithdrawalRequest {
     pub claimed: bool,
 }
 
+/// Registry entry for a validator the pool delegates to. `Validators` keeps
+/// the list of registered addresses.
+#[derive(Clone)]
+#[contracttype]
+pub struct ValidatorInfo {
+    pub address: Address,
+    pub commission_bps: u32,
+    /// Share of the staked total this validator should hold, in bps.
+    pub target_weight_bps: u32,
+    /// Inactive validators keep their entry but receive no target allocation.
+    pub active: bool,
+    /// XLM currently delegated to this validator, as reported by the validator service.
+    pub allocated: i128,
+}
+
+/// A recipient's share of the protocol fee left after the insurance cut; the
+/// unsplit remainder goes to the treasury.
+#[derive(Clone)]
+#[contracttype]
+pub struct FeeSplit {
+    pub recipient: Address,
+    pub bps: u32,
+}
+
+/// Snapshot comparing the contract's XLM balance with what it owes.
+/// `LiquidityBuffer` is not listed: it is the part of the balance currently
+/// free for instant exits, not a separate claim.
+#[derive(Clone)]
+#[contracttype]
+pub struct ReconcileReport {
+    /// XLM actually held by the contract.
+    pub token_balance: i128,
+    /// XLM backing sXLM, streaming rewards included.
+    pub staked: i128,
+    /// XLM owed to open withdrawal requests.
+    pub pending_withdrawals: i128,
+    /// Treasury balance, unclaimed fee splits and the insurance fund.
+    pub protocol_balances: i128,
+    /// Funded rewards waiting in the rate-change queue, owed to the pool or
+    /// back to their funder.
+    pub queued_rewards: i128,
+    /// Buffer liquidity owed back to its providers.
+    pub liquidity_provided: i128,
+    /// `token_balance` minus everything above; negative means a deficit.
+    pub surplus: i128,
+}
+
+/// Exchange rate as it stood at the end of the last call in `ledger` that
+/// changed it.
+#[derive(Clone)]
+#[contracttype]
+pub struct RateCheckpoint {
+    pub ledger: u32,
+    pub rate: i128,
+}
+
+/// Bounds on how far a single reward or slash may move the exchange rate, in
+/// bps of the rate. A cap of 0 disables that check. Calls over a cap are
+/// queued for `delay_ledgers` instead of applied. While a slash is queued,
+/// deposits, withdrawals and cancels are refused so nobody moves in or out at
+/// the pre-slash rate.
+#[derive(Clone)]
+#[contracttype]
+pub struct RateLimits {
+    /// Largest move a single call may cause.
+    pub per_call_bps: u32,
+    /// Largest move against the rate `window_ledgers` ago.
+    pub window_bps: u32,
+    pub window_ledgers: u32,
+    pub delay_ledgers: u32,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub enum RateChangeKind {
+    /// `add_rewards`; the XLM arrives separately.
+    Reward,
+    /// `add_rewards_funded`; the XLM is already held and is refunded to the
+    /// funder on veto.
+    FundedReward(Address),
+    /// `apply_slashing`.
+    Slash,
+    /// `slash_validator`.
+    ValidatorSlash(Address),
+}
+
+/// A reward or slash that exceeded the rate limits, waiting out the timelock.
+#[derive(Clone)]
+#[contracttype]
+pub struct PendingRateChange {
+    pub id: u64,
+    pub kind: RateChangeKind,
+    pub amount: i128,
+    /// First ledger at which `execute_rate_change` may apply it.
+    pub eta: u32,
+}
+
+/// Buffer accounting returned by `buffer_stats`.
+#[derive(Clone)]
+#[contracttype]
+pub struct BufferStats {
+    pub buffer: i128,
+    pub target: i128,
+    pub liquidity_provided: i128,
+    pub pending_withdrawals: i128,
+    /// Share of provided liquidity already drawn by instant exits, in bps.
+    pub utilization_bps: i128,
+    /// Buffer relative to open withdrawal liabilities, in bps; full coverage
+    /// when nothing is queued.
+    pub coverage_bps: i128,
+}
+
+/// Quote returned by `preview_withdrawal`.
+#[derive(Clone)]
+#[contracttype]
+pub struct WithdrawalPreview {
+    pub xlm_amount: i128,
+    /// True when the liquidity buffer covers the exit right away.
+    pub instant: bool,
+    /// Ledger the XLM becomes claimable; the current ledger for instant exits.
+    pub unlock_ledger: u32,
+}
+
 // --- TTL helpers ---
 
 fn extend_instance(env: &Env) {
@@ -54,12 +278,6 @@ fn extend_instance(env: &Env) {
         .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
 }
 
-fn extend_queue(env: &Env) {
-    env.storage()
-        .persistent()
-        .extend_ttl(&DataKey::WithdrawalQueue, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
-}
-
 // --- Storage helpers ---
 
 fn read_i128(env: &Env, key: &DataKey) -> i128 {
@@ -70,6 +288,13 @@ fn write_i128(env: &Env, key: &DataKey, val: i128) {
     env.storage().instance().set(key, &val);
 }
 
+fn read_schema_version(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::SchemaVersion)
+        .unwrap_or(0)
+}
+
 fn read_admin(env: &Env) -> Address {
     env.storage().instance().get(&DataKey::Admin).unwrap()
 }
@@ -82,6 +307,10 @@ fn read_native_token(env: &Env) -> Address {
     env.storage().instance().get(&DataKey::NativeToken).unwrap()
 }
 
+fn read_treasury(env: &Env) -> Address {
+    env.storage().instance().get(&DataKey::Treasury).unwrap()
+}
+
 fn read_cooldown(env: &Env) -> u32 {
     env.storage()
         .instance()
@@ -102,593 +331,4211 @@ fn require_not_paused(env: &Env) {
     }
 }
 
-fn next_withdrawal_id(env: &Env) -> u64 {
-    let id: u64 = env
+/// Whether `operation` is paused, on its own or by the global `pause`, which
+/// covers deposits and withdrawals.
+fn is_operation_paused(env: &Env, operation: Operation) -> bool {
+    let paused: bool = env
         .storage()
         .instance()
-        .get(&DataKey::WithdrawalCounter)
-        .unwrap_or(0);
-    env.storage()
-        .instance()
-        .set(&DataKey::WithdrawalCounter, &(id + 1));
-    id
+        .get(&DataKey::OperationPaused(operation))
+        .unwrap_or(false);
+    paused
+        || (is_paused(env)
+            && matches!(
+                operation,
+                Operation::Deposit | Operation::InstantWithdrawal | Operation::DelayedWithdrawal
+            ))
 }
 
-fn get_withdrawal_queue(env: &Env) -> Map<u64, WithdrawalRequest> {
-    let queue: Map<u64, WithdrawalRequest> = env
-        .storage()
-        .persistent()
-        .get(&DataKey::WithdrawalQueue)
-        .unwrap_or(Map::new(env));
-    // Extend TTL whenever we read the queue
-    if env.storage().persistent().has(&DataKey::WithdrawalQueue) {
-        extend_queue(env);
+fn require_operation(env: &Env, operation: Operation) {
+    if is_operation_paused(env, operation) {
+        panic!("operation is paused");
     }
-    queue
 }
 
-fn set_withdrawal_queue(env: &Env, queue: &Map<u64, WithdrawalRequest>) {
+fn is_insolvent(env: &Env) -> bool {
     env.storage()
-        .persistent()
-        .set(&DataKey::WithdrawalQueue, queue);
-    extend_queue(env);
+        .instance()
+        .get(&DataKey::Insolvent)
+        .unwrap_or(false)
 }
 
-#[contract]
-pub struct StakingContract;
-
-#[contractimpl]
-impl StakingContract {
-    /// Initialize the staking contract.
-    pub fn initialize(
-        env: Env,
-        admin: Address,
-        sxlm_token: Address,
-        native_token: Address,
-        cooldown_period: u32,
-    ) {
-        if env.storage().instance().has(&DataKey::Initialized) {
-            panic!("already initialized");
-        }
-        env.storage().instance().set(&DataKey::Initialized, &true);
-        env.storage().instance().set(&DataKey::Admin, &admin);
-        env.storage().instance().set(&DataKey::SxlmToken, &sxlm_token);
-        env.storage().instance().set(&DataKey::NativeToken, &native_token);
-        env.storage().instance().set(&DataKey::CooldownPeriod, &cooldown_period);
-        env.storage().instance().set(&DataKey::Paused, &false);
-        env.storage().instance().set(&DataKey::Treasury, &admin);
-        write_i128(&env, &DataKey::TotalXlmStaked, 0);
-        write_i128(&env, &DataKey::TotalSxlmSupply, 0);
-        write_i128(&env, &DataKey::LiquidityBuffer, 0);
-        write_i128(&env, &DataKey::TreasuryBalance, 0);
-        extend_instance(&env);
-    }
-
-    /// Upgrade the contract WASM. Only callable by admin.
-    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        env.deployer().update_current_contract_wasm(new_wasm_hash);
-    }
-
-    /// Bump instance TTL — can be called by anyone to keep the contract alive.
-    pub fn bump_instance(env: Env) {
-        extend_instance(&env);
+fn require_solvent(env: &Env) {
+    if is_insolvent(env) {
+        panic!("pool is insolvent");
     }
+}
 
-    // ==========================================================
-    // Core staking functions
-    // ==========================================================
+fn read_queued_slashes(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::QueuedSlashes)
+        .unwrap_or(0)
+}
 
-    /// Deposit XLM and receive sXLM tokens.
-    pub fn deposit(env: Env, user: Address, xlm_amount: i128) {
-        require_not_paused(&env);
-        user.require_auth();
-        if xlm_amount <= 0 {
-            panic!("deposit amount must be positive");
-        }
-        extend_instance(&env);
+fn write_queued_slashes(env: &Env, count: u32) {
+    env.storage().instance().set(&DataKey::QueuedSlashes, &count);
+}
 
-        let native_token_addr = read_native_token(&env);
-        let xlm_client = token::Client::new(&env, &native_token_addr);
-        xlm_client.transfer(&user, &env.current_contract_address(), &xlm_amount);
+/// Refuse calls priced at the exchange rate while a slash waits out the
+/// timelock.
+fn require_no_queued_slash(env: &Env) {
+    if read_queued_slashes(env) > 0 {
+        panic!("slash pending");
+    }
+}
 
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+fn read_role_holder(env: &Env, role: Role) -> Address {
+    env.storage()
+        .instance()
+        .get(&DataKey::RoleHolder(role))
+        .unwrap_or_else(|| read_admin(env))
+}
 
-        let sxlm_to_mint = if total_supply == 0 || total_staked == 0 {
-            xlm_amount
-        } else {
-            xlm_amount * total_supply / total_staked
-        };
+/// Require auth from the holder of `role` and return it.
+fn require_role(env: &Env, role: Role) -> Address {
+    let holder = read_role_holder(env, role);
+    holder.require_auth();
+    holder
+}
 
-        if sxlm_to_mint <= 0 {
-            panic!("mint amount too small");
-        }
+fn read_rate_limits(env: &Env) -> RateLimits {
+    env.storage()
+        .instance()
+        .get(&DataKey::RateLimits)
+        .unwrap_or(RateLimits {
+            per_call_bps: 0,
+            window_bps: 0,
+            window_ledgers: 0,
+            delay_ledgers: 0,
+        })
+}
 
-        write_i128(&env, &DataKey::TotalXlmStaked, total_staked + xlm_amount);
-        write_i128(&env, &DataKey::TotalSxlmSupply, total_supply + sxlm_to_mint);
+/// Whether moving the rate from `current` to `projected` stays within
+/// `cap_bps` of `current`.
+fn within_bps(current: i128, projected: i128, cap_bps: u32) -> bool {
+    (projected - current).abs() * BPS_DENOMINATOR <= current * cap_bps as i128
+}
 
-        let sxlm_token = read_sxlm_token(&env);
-        let sxlm_client = SxlmTokenClient::new(&env, &sxlm_token);
-        sxlm_client.mint(&user, &sxlm_to_mint);
+/// Staked total once every unvested reward has streamed in.
+fn staked_with_unvested(env: &Env) -> i128 {
+    read_i128(env, &DataKey::TotalXlmStaked) + read_i128(env, &DataKey::UnvestedRewards)
+}
 
-        env.events().publish(
-            (soroban_sdk::symbol_short!("deposit"),),
-            (user, xlm_amount, sxlm_to_mint),
-        );
+/// Whether a change taking the staked total, unvested rewards included, to
+/// `new_staked` is within the configured rate limits. Rewards still vesting
+/// count as applied, so small rewards cannot pile up behind the vesting
+/// schedule. Must be called after `accrue_rewards`.
+fn rate_change_allowed(env: &Env, new_staked: i128) -> bool {
+    let limits = read_rate_limits(env);
+    let total_supply = read_i128(env, &DataKey::TotalSxlmSupply);
+    if total_supply == 0 {
+        return true;
     }
+    let current = exchange_rate(staked_with_unvested(env), total_supply);
+    let projected = exchange_rate(new_staked, total_supply);
 
-    /// Request withdrawal: burns sXLM and returns XLM.
-    pub fn request_withdrawal(env: Env, user: Address, sxlm_amount: i128) {
-        require_not_paused(&env);
-        user.require_auth();
-        if sxlm_amount <= 0 {
-            panic!("withdrawal amount must be positive");
+    if limits.per_call_bps > 0 && !within_bps(current, projected, limits.per_call_bps) {
+        return false;
+    }
+    if limits.window_bps > 0 {
+        let window_start = env.ledger().sequence().saturating_sub(limits.window_ledgers);
+        let history = read_rate_history(env);
+        let reference = rate_at(&history, window_start)
+            .or(history.first().map(|checkpoint| checkpoint.rate))
+            .unwrap_or(current);
+        if !within_bps(reference, projected, limits.window_bps) {
+            return false;
         }
-        extend_instance(&env);
+    }
+    true
+}
 
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+/// Unfunded rewards are refused once `set_require_funded_rewards` is on,
+/// including ones queued before it was turned on.
+fn require_unfunded_rewards_allowed(env: &Env) {
+    let funded_only: bool = env
+        .storage()
+        .instance()
+        .get(&DataKey::RequireFundedRewards)
+        .unwrap_or(false);
+    if funded_only {
+        panic!("rewards must be funded");
+    }
+}
 
-        if total_supply == 0 {
-            panic!("no sXLM in circulation");
-        }
+/// Staked total, unvested rewards included, after a reward of `amount` net
+/// of the protocol fee.
+fn staked_after_reward(env: &Env, amount: i128) -> i128 {
+    let fee = amount * read_protocol_fee_bps(env) / BPS_DENOMINATOR;
+    staked_with_unvested(env) + amount - fee
+}
 
-        let xlm_to_return = sxlm_amount * total_staked / total_supply;
-        if xlm_to_return <= 0 {
-            panic!("return amount too small");
-        }
+/// Staked total, unvested rewards included, after a slash of `amount` net of
+/// insurance cover.
+fn staked_after_slash(env: &Env, amount: i128) -> i128 {
+    let covered = amount.min(read_i128(env, &DataKey::InsuranceFund));
+    staked_with_unvested(env) - (amount - covered)
+}
 
-        let sxlm_token = read_sxlm_token(&env);
-        let sxlm_client = SxlmTokenClient::new(&env, &sxlm_token);
-        sxlm_client.burn(&user, &sxlm_amount);
+/// Hold back a reward or slash until the timelock passes. Returns its id.
+fn queue_rate_change(env: &Env, kind: RateChangeKind, amount: i128) -> u64 {
+    let id: u64 = env
+        .storage()
+        .instance()
+        .get(&DataKey::RateChangeCounter)
+        .unwrap_or(0);
+    env.storage()
+        .instance()
+        .set(&DataKey::RateChangeCounter, &(id + 1));
 
-        write_i128(&env, &DataKey::TotalSxlmSupply, total_supply - sxlm_amount);
+    let eta = env.ledger().sequence() + read_rate_limits(env).delay_ledgers;
+    let key = DataKey::PendingRateChange(id);
+    env.storage().persistent().set(
+        &key,
+        &PendingRateChange {
+            id,
+            kind,
+            amount,
+            eta,
+        },
+    );
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
 
-        let buffer = read_i128(&env, &DataKey::LiquidityBuffer);
+    env.events().publish(
+        (soroban_sdk::symbol_short!("rate_q"),),
+        (id, amount, eta),
+    );
+    id
+}
 
-        if buffer >= xlm_to_return {
-            write_i128(&env, &DataKey::LiquidityBuffer, buffer - xlm_to_return);
-            write_i128(&env, &DataKey::TotalXlmStaked, total_staked - xlm_to_return);
+fn read_pending_rate_change(env: &Env, id: u64) -> PendingRateChange {
+    env.storage()
+        .persistent()
+        .get(&DataKey::PendingRateChange(id))
+        .expect("rate change not found")
+}
 
-            let native_token_addr = read_native_token(&env);
-            let xlm_client = token::Client::new(&env, &native_token_addr);
-            xlm_client.transfer(&env.current_contract_address(), &user, &xlm_to_return);
+fn read_validator_list(env: &Env) -> Vec<Address> {
+    env.storage()
+        .instance()
+        .get(&DataKey::Validators)
+        .unwrap_or(Vec::new(env))
+}
 
-            env.events().publish(
-                (soroban_sdk::symbol_short!("instant"),),
-                (user, xlm_to_return),
-            );
-        } else {
-            let cooldown = read_cooldown(&env);
-            let unlock_ledger = env.ledger().sequence() + cooldown;
-            let id = next_withdrawal_id(&env);
+fn read_validator(env: &Env, validator: &Address) -> ValidatorInfo {
+    let key = DataKey::ValidatorInfo(validator.clone());
+    let info: ValidatorInfo = env
+        .storage()
+        .persistent()
+        .get(&key)
+        .expect("validator not registered");
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    info
+}
 
-            let request = WithdrawalRequest {
-                id,
-                user: user.clone(),
-                xlm_amount: xlm_to_return,
-                unlock_ledger,
-                claimed: false,
-            };
+fn write_validator(env: &Env, info: &ValidatorInfo) {
+    let key = DataKey::ValidatorInfo(info.address.clone());
+    env.storage().persistent().set(&key, info);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
 
-            let mut queue = get_withdrawal_queue(&env);
-            queue.set(id, request);
-            set_withdrawal_queue(&env, &queue);
+    env.events().publish(
+        (soroban_sdk::symbol_short!("val_upd"),),
+        info.clone(),
+    );
+}
 
-            env.events().publish(
-                (soroban_sdk::symbol_short!("delayed"),),
-                (user, xlm_to_return, id, unlock_ledger),
-            );
-        }
+fn register_validator(env: &Env, info: &ValidatorInfo) {
+    if info.commission_bps as i128 > BPS_DENOMINATOR {
+        panic!("invalid commission");
+    }
+    let mut list = read_validator_list(env);
+    if list.contains(&info.address) {
+        panic!("validator already registered");
     }
+    if list.len() >= MAX_VALIDATORS {
+        panic!("too many validators");
+    }
+    list.push_back(info.address.clone());
+    env.storage().instance().set(&DataKey::Validators, &list);
+    write_validator(env, info);
+}
 
-    /// Claim a delayed withdrawal after cooldown has expired.
-    pub fn claim_withdrawal(env: Env, user: Address, withdrawal_id: u64) {
-        user.require_auth();
-        extend_instance(&env);
+fn unregister_validator(env: &Env, validator: &Address) {
+    let info = read_validator(env, validator);
+    if info.allocated != 0 {
+        panic!("validator still has allocated stake");
+    }
+    let mut list = read_validator_list(env);
+    if let Some(index) = list.first_index_of(validator) {
+        list.remove(index);
+    }
+    env.storage().instance().set(&DataKey::Validators, &list);
+    env.storage()
+        .persistent()
+        .remove(&DataKey::ValidatorInfo(validator.clone()));
 
-        let mut queue = get_withdrawal_queue(&env);
-        let mut request = queue.get(withdrawal_id).expect("withdrawal not found");
+    env.events().publish(
+        (soroban_sdk::symbol_short!("val_rm"),),
+        validator.clone(),
+    );
+}
 
-        if request.user != user {
-            panic!("not your withdrawal");
-        }
-        if request.claimed {
-            panic!("already claimed");
-        }
-        if env.ledger().sequence() < request.unlock_ledger {
-            panic!("cooldown not expired");
+/// Panic if the target weights of active validators add up to more than 100%.
+fn require_valid_weights(env: &Env) {
+    let mut total: i128 = 0;
+    for validator in read_validator_list(env).iter() {
+        let info = read_validator(env, &validator);
+        if info.active {
+            total += info.target_weight_bps as i128;
         }
+    }
+    if total > BPS_DENOMINATOR {
+        panic!("total validator weight exceeds 100%");
+    }
+}
 
-        request.claimed = true;
-        queue.set(withdrawal_id, request.clone());
-        set_withdrawal_queue(&env, &queue);
+fn read_protocol_fee_bps(env: &Env) -> i128 {
+    env.storage()
+        .instance()
+        .get(&DataKey::ProtocolFeeBps)
+        .unwrap_or(DEFAULT_PROTOCOL_FEE_BPS)
+}
 
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        write_i128(&env, &DataKey::TotalXlmStaked, total_staked - request.xlm_amount);
+fn read_fee_splits(env: &Env) -> Vec<FeeSplit> {
+    env.storage()
+        .instance()
+        .get(&DataKey::FeeSplits)
+        .unwrap_or(Vec::new(env))
+}
 
-        let native_token_addr = read_native_token(&env);
-        let xlm_client = token::Client::new(&env, &native_token_addr);
-        xlm_client.transfer(&env.current_contract_address(), &user, &request.xlm_amount);
+fn read_buffer_contribution(env: &Env, provider: &Address) -> i128 {
+    let key = DataKey::BufferContribution(provider.clone());
+    let amount = env.storage().persistent().get(&key).unwrap_or(0);
+    if amount > 0 {
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    }
+    amount
+}
 
-        env.events().publish(
-            (soroban_sdk::symbol_short!("claimed"),),
-            (user, request.xlm_amount, withdrawal_id),
-        );
+fn write_buffer_contribution(env: &Env, provider: &Address, amount: i128) {
+    let key = DataKey::BufferContribution(provider.clone());
+    if amount == 0 {
+        env.storage().persistent().remove(&key);
+    } else {
+        env.storage().persistent().set(&key, &amount);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
     }
+}
 
-    // ==========================================================
-    // Reward & Fee functions
-    // ==========================================================
+fn read_target_buffer_bps(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::TargetBufferBps)
+        .unwrap_or(0)
+}
 
-    /// Add staking rewards — takes protocol fee (10%), remainder increases
-    /// total_xlm_staked, raising the exchange rate.
-    pub fn add_rewards(env: Env, amount: i128) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        if amount <= 0 {
-            panic!("reward amount must be positive");
+fn target_buffer(env: &Env, total_staked: i128) -> i128 {
+    total_staked * read_target_buffer_bps(env) as i128 / BPS_DENOMINATOR
+}
+
+fn read_insurance_fee_bps(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::InsuranceFeeBps)
+        .unwrap_or(0)
+}
+
+fn read_vesting_period(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::RewardVestingPeriod)
+        .unwrap_or(0)
+}
+
+/// Part of the unvested rewards released since the last accrual. Rewards vest
+/// linearly from the last accrual to `RewardVestingEnd`.
+fn vested_rewards(env: &Env) -> i128 {
+    let unvested = read_i128(env, &DataKey::UnvestedRewards);
+    if unvested == 0 {
+        return 0;
+    }
+    let now = env.ledger().sequence();
+    let last: u32 = env.storage().instance().get(&DataKey::RewardLastAccrual).unwrap_or(now);
+    let end: u32 = env.storage().instance().get(&DataKey::RewardVestingEnd).unwrap_or(now);
+    if now >= end {
+        unvested
+    } else if now <= last {
+        0
+    } else {
+        unvested * (now - last) as i128 / (end - last) as i128
+    }
+}
+
+/// Move vested rewards into `TotalXlmStaked`. Every entry point that reads or
+/// writes the staked total calls this first.
+fn accrue_rewards(env: &Env) {
+    let vested = vested_rewards(env);
+    env.storage()
+        .instance()
+        .set(&DataKey::RewardLastAccrual, &env.ledger().sequence());
+    if vested > 0 {
+        let unvested = read_i128(env, &DataKey::UnvestedRewards);
+        write_i128(env, &DataKey::UnvestedRewards, unvested - vested);
+        let total_staked = read_i128(env, &DataKey::TotalXlmStaked);
+        write_i128(env, &DataKey::TotalXlmStaked, total_staked + vested);
+    }
+}
+
+/// Staked total including rewards vested since the last accrual, for views.
+fn current_total_staked(env: &Env) -> i128 {
+    read_i128(env, &DataKey::TotalXlmStaked) + vested_rewards(env)
+}
+
+fn exchange_rate(total_staked: i128, total_supply: i128) -> i128 {
+    if total_supply == 0 {
+        RATE_PRECISION
+    } else {
+        total_staked * RATE_PRECISION / total_supply
+    }
+}
+
+fn read_rate_history(env: &Env) -> Vec<RateCheckpoint> {
+    let key = DataKey::RateHistory;
+    match env.storage().persistent().get(&key) {
+        Some(history) => {
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+            history
         }
-        extend_instance(&env);
+        None => Vec::new(env),
+    }
+}
 
-        let fee = amount * PROTOCOL_FEE_BPS / BPS_DENOMINATOR;
-        let net_reward = amount - fee;
+/// Rate of the last checkpoint at or before `ledger`, if the history reaches
+/// back that far.
+fn rate_at(history: &Vec<RateCheckpoint>, ledger: u32) -> Option<i128> {
+    for checkpoint in history.iter().rev() {
+        if checkpoint.ledger <= ledger {
+            return Some(checkpoint.rate);
+        }
+    }
+    None
+}
 
-        let treasury_bal = read_i128(&env, &DataKey::TreasuryBalance);
-        write_i128(&env, &DataKey::TreasuryBalance, treasury_bal + fee);
+/// Checkpoint the current exchange rate. Several changes within one ledger
+/// share a checkpoint, and an unchanged rate is not recorded again.
+fn record_rate(env: &Env) {
+    let rate = exchange_rate(
+        read_i128(env, &DataKey::TotalXlmStaked),
+        read_i128(env, &DataKey::TotalSxlmSupply),
+    );
+    let ledger = env.ledger().sequence();
+    let mut history = read_rate_history(env);
 
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        write_i128(&env, &DataKey::TotalXlmStaked, total_staked + net_reward);
+    if let Some(last) = history.last() {
+        if last.ledger == ledger {
+            history.pop_back();
+        } else if last.rate == rate {
+            return;
+        }
+    }
+    if history.len() >= MAX_RATE_CHECKPOINTS {
+        history.pop_front();
+    }
+    history.push_back(RateCheckpoint { ledger, rate });
+
+    let key = DataKey::RateHistory;
+    env.storage().persistent().set(&key, &history);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// sXLM minted for `xlm_amount` at the current pool ratio, rounded down so
+/// the depositor never receives more than their XLM is worth.
+fn sxlm_for_xlm(xlm_amount: i128, total_staked: i128, total_supply: i128) -> i128 {
+    if total_supply == 0 || total_staked == 0 {
+        xlm_amount
+    } else {
+        xlm_amount * total_supply / total_staked
+    }
+}
+
+/// XLM returned for burning `sxlm_amount` at the current pool ratio, rounded
+/// down so the remaining holders keep any dust.
+fn xlm_for_sxlm(sxlm_amount: i128, total_staked: i128, total_supply: i128) -> i128 {
+    if total_supply == 0 {
+        0
+    } else {
+        sxlm_amount * total_staked / total_supply
+    }
+}
+
+/// Part of a deposit's mint that is locked in the contract: `LOCKED_SXLM` on
+/// the very first deposit, nothing afterwards.
+fn locked_sxlm(total_supply: i128) -> i128 {
+    if total_supply == 0 {
+        LOCKED_SXLM
+    } else {
+        0
+    }
+}
+
+fn next_withdrawal_id(env: &Env) -> u64 {
+    let id: u64 = env
+        .storage()
+        .instance()
+        .get(&DataKey::WithdrawalCounter)
+        .unwrap_or(0);
+    env.storage()
+        .instance()
+        .set(&DataKey::WithdrawalCounter, &(id + 1));
+    id
+}
+
+fn read_withdrawal(env: &Env, id: u64) -> Option<WithdrawalRequest> {
+    let key = DataKey::Withdrawal(id);
+    let request: Option<WithdrawalRequest> = env.storage().persistent().get(&key);
+    if request.is_some() {
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+        let sxlm_key = DataKey::WithdrawalSxlm(id);
+        if env.storage().persistent().has(&sxlm_key) {
+            env.storage().persistent().extend_ttl(
+                &sxlm_key,
+                PERSISTENT_LIFETIME_THRESHOLD,
+                PERSISTENT_BUMP_AMOUNT,
+            );
+        }
+    }
+    request
+}
+
+/// sXLM burned to open request `id`. Requests from before this was recorded
+/// have none.
+fn read_withdrawal_sxlm(env: &Env, id: u64) -> Option<i128> {
+    env.storage().persistent().get(&DataKey::WithdrawalSxlm(id))
+}
+
+fn write_withdrawal_sxlm(env: &Env, id: u64, sxlm_amount: i128) {
+    let key = DataKey::WithdrawalSxlm(id);
+    env.storage().persistent().set(&key, &sxlm_amount);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+fn write_withdrawal(env: &Env, request: &WithdrawalRequest) {
+    let key = DataKey::Withdrawal(request.id);
+    env.storage().persistent().set(&key, request);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+fn withdrawal_counter(env: &Env) -> u64 {
+    env.storage()
+        .instance()
+        .get(&DataKey::WithdrawalCounter)
+        .unwrap_or(0)
+}
+
+fn read_queue_head(env: &Env) -> u64 {
+    env.storage()
+        .instance()
+        .get(&DataKey::QueueHead)
+        .unwrap_or(0)
+}
+
+/// Move the queue head past ids that are no longer stored, a bounded number of
+/// steps at a time.
+fn advance_queue_head(env: &Env) {
+    let count = withdrawal_counter(env);
+    let mut head = read_queue_head(env);
+    let mut steps = 0;
+    while head < count
+        && steps < MAX_PAGE_SIZE
+        && !env.storage().persistent().has(&DataKey::Withdrawal(head))
+    {
+        head += 1;
+        steps += 1;
+    }
+    env.storage().instance().set(&DataKey::QueueHead, &head);
+}
+
+/// Store a new open request and index it under its owner.
+fn open_withdrawal(env: &Env, request: &WithdrawalRequest) {
+    write_withdrawal(env, request);
+
+    let mut user_ids = read_user_withdrawals(env, &request.user);
+    user_ids.push_back(request.id);
+    write_user_withdrawals(env, &request.user, &user_ids);
+
+    let pending = read_i128(env, &DataKey::PendingWithdrawalXlm);
+    write_i128(env, &DataKey::PendingWithdrawalXlm, pending + request.xlm_amount);
+}
+
+/// Prune a settled request: drop its entry, its index slot and its liability.
+fn close_withdrawal(env: &Env, request: &WithdrawalRequest) {
+    env.storage()
+        .persistent()
+        .remove(&DataKey::Withdrawal(request.id));
+    env.storage()
+        .persistent()
+        .remove(&DataKey::WithdrawalApproval(request.id));
+    env.storage()
+        .persistent()
+        .remove(&DataKey::WithdrawalSxlm(request.id));
+    remove_user_withdrawal(env, &request.user, request.id);
+
+    let pending = read_i128(env, &DataKey::PendingWithdrawalXlm);
+    write_i128(env, &DataKey::PendingWithdrawalXlm, pending - request.xlm_amount);
+
+    if request.id == read_queue_head(env) {
+        advance_queue_head(env);
+    }
+}
+
+fn read_withdrawal_approval(env: &Env, id: u64) -> Option<Address> {
+    env.storage()
+        .persistent()
+        .get(&DataKey::WithdrawalApproval(id))
+}
+
+/// Refuse to add a request to `user`'s index on someone else's behalf once
+/// it is full, so nobody can grow another user's index without bound.
+fn require_inbound_room(env: &Env, user: &Address) {
+    if read_user_withdrawals(env, user).len() >= MAX_INBOUND_WITHDRAWALS {
+        panic!("recipient has too many open withdrawals");
+    }
+}
+
+/// Hand an open request to a new owner and clear any outstanding approval.
+fn move_withdrawal(env: &Env, mut request: WithdrawalRequest, to: &Address) {
+    let from = request.user.clone();
+    if from != *to {
+        require_inbound_room(env, to);
+    }
+    remove_user_withdrawal(env, &from, request.id);
+    env.storage()
+        .persistent()
+        .remove(&DataKey::WithdrawalApproval(request.id));
+
+    request.user = to.clone();
+    write_withdrawal(env, &request);
+    let mut user_ids = read_user_withdrawals(env, to);
+    user_ids.push_back(request.id);
+    write_user_withdrawals(env, to, &user_ids);
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("wd_xfer"),),
+        (from, to.clone(), request.id),
+    );
+}
+
+fn remove_user_withdrawal(env: &Env, user: &Address, id: u64) {
+    let mut user_ids = read_user_withdrawals(env, user);
+    if let Some(index) = user_ids.first_index_of(id) {
+        user_ids.remove(index);
+        if user_ids.is_empty() {
+            env.storage()
+                .persistent()
+                .remove(&DataKey::UserWithdrawals(user.clone()));
+        } else {
+            write_user_withdrawals(env, user, &user_ids);
+        }
+    }
+}
+
+fn read_user_withdrawals(env: &Env, user: &Address) -> Vec<u64> {
+    let key = DataKey::UserWithdrawals(user.clone());
+    let ids: Vec<u64> = env
+        .storage()
+        .persistent()
+        .get(&key)
+        .unwrap_or(Vec::new(env));
+    if !ids.is_empty() {
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    }
+    ids
+}
 
+fn write_user_withdrawals(env: &Env, user: &Address, ids: &Vec<u64>) {
+    let key = DataKey::UserWithdrawals(user.clone());
+    env.storage().persistent().set(&key, ids);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+fn clamp_page_size(limit: u32) -> u32 {
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        MAX_PAGE_SIZE
+    } else {
+        limit
+    }
+}
+
+fn require_before_deadline(env: &Env, deadline_ledger: u32) {
+    if env.ledger().sequence() > deadline_ledger {
+        panic!("deadline passed");
+    }
+}
+
+/// Take `xlm_amount` from `payer` and mint sXLM to `beneficiary` at the
+/// current rate. Returns the sXLM minted.
+fn execute_deposit(
+    env: &Env,
+    payer: &Address,
+    beneficiary: &Address,
+    xlm_amount: i128,
+    min_sxlm_out: i128,
+) -> i128 {
+    if xlm_amount <= 0 {
+        panic!("deposit amount must be positive");
+    }
+    require_operation(env, Operation::Deposit);
+    require_solvent(env);
+    require_no_queued_slash(env);
+    accrue_rewards(env);
+
+    let total_staked = read_i128(env, &DataKey::TotalXlmStaked);
+    let total_supply = read_i128(env, &DataKey::TotalSxlmSupply);
+
+    let minted = sxlm_for_xlm(xlm_amount, total_staked, total_supply);
+    let locked = locked_sxlm(total_supply);
+    let sxlm_to_mint = minted - locked;
+
+    if sxlm_to_mint <= 0 {
+        panic!("mint amount too small");
+    }
+    if sxlm_to_mint < min_sxlm_out {
+        panic!("slippage: output below minimum");
+    }
+
+    let native_token_addr = read_native_token(env);
+    let xlm_client = token::Client::new(env, &native_token_addr);
+    xlm_client.transfer(payer, &env.current_contract_address(), &xlm_amount);
+
+    write_i128(env, &DataKey::TotalXlmStaked, total_staked + xlm_amount);
+    write_i128(env, &DataKey::TotalSxlmSupply, total_supply + minted);
+
+    // New deposits top the buffer up to its target before anything else.
+    let buffer = read_i128(env, &DataKey::LiquidityBuffer);
+    let shortfall = target_buffer(env, total_staked + xlm_amount) - buffer;
+    if shortfall > 0 {
+        write_i128(env, &DataKey::LiquidityBuffer, buffer + shortfall.min(xlm_amount));
+    }
+
+    let sxlm_token = read_sxlm_token(env);
+    let sxlm_client = SxlmTokenClient::new(env, &sxlm_token);
+    sxlm_client.mint(beneficiary, &sxlm_to_mint);
+    if locked > 0 {
+        sxlm_client.mint(&env.current_contract_address(), &locked);
+    }
+
+    record_rate(env);
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("deposit"),),
+        (beneficiary.clone(), xlm_amount, sxlm_to_mint),
+    );
+    if payer != beneficiary {
         env.events().publish(
-            (soroban_sdk::symbol_short!("rewards"),),
-            (amount, net_reward, fee),
+            (soroban_sdk::symbol_short!("dep_for"),),
+            (payer.clone(), beneficiary.clone(), xlm_amount),
         );
     }
 
-    /// Withdraw protocol fees to the admin address.
-    /// If amount > 0, withdraw that specific amount; if 0, withdraw all.
-    pub fn withdraw_fees(env: Env, amount: i128) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
+    sxlm_to_mint
+}
 
-        let treasury_bal = read_i128(&env, &DataKey::TreasuryBalance);
+/// Burn `sxlm_amount` from `user` and pay `recipient` from the buffer, or queue
+/// a withdrawal request owned by `recipient` when the buffer is short.
+/// Returns the XLM owed.
+fn execute_withdrawal(
+    env: &Env,
+    user: &Address,
+    recipient: &Address,
+    sxlm_amount: i128,
+    min_xlm_out: i128,
+) -> i128 {
+    if sxlm_amount <= 0 {
+        panic!("withdrawal amount must be positive");
+    }
+    require_solvent(env);
+    require_no_queued_slash(env);
+    accrue_rewards(env);
 
-        let withdraw_amount = if amount <= 0 {
-            treasury_bal
-        } else {
-            amount
-        };
+    let total_staked = read_i128(env, &DataKey::TotalXlmStaked);
+    let total_supply = read_i128(env, &DataKey::TotalSxlmSupply);
 
-        if withdraw_amount <= 0 {
-            panic!("no fees to withdraw");
+    if total_supply == 0 {
+        panic!("no sXLM in circulation");
+    }
+
+    let xlm_to_return = xlm_for_sxlm(sxlm_amount, total_staked, total_supply);
+    if xlm_to_return <= 0 {
+        panic!("return amount too small");
+    }
+    if xlm_to_return < min_xlm_out {
+        panic!("slippage: output below minimum");
+    }
+
+    let sxlm_token = read_sxlm_token(env);
+    let sxlm_client = SxlmTokenClient::new(env, &sxlm_token);
+    sxlm_client.burn(user, &sxlm_amount);
+
+    write_i128(env, &DataKey::TotalSxlmSupply, total_supply - sxlm_amount);
+
+    let buffer = read_i128(env, &DataKey::LiquidityBuffer);
+    let instant = buffer >= xlm_to_return && !is_operation_paused(env, Operation::InstantWithdrawal);
+
+    if instant {
+        write_i128(env, &DataKey::LiquidityBuffer, buffer - xlm_to_return);
+        write_i128(env, &DataKey::TotalXlmStaked, total_staked - xlm_to_return);
+
+        let native_token_addr = read_native_token(env);
+        let xlm_client = token::Client::new(env, &native_token_addr);
+        xlm_client.transfer(&env.current_contract_address(), recipient, &xlm_to_return);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("instant"),),
+            (user.clone(), xlm_to_return),
+        );
+    } else {
+        require_operation(env, Operation::DelayedWithdrawal);
+        if user != recipient {
+            require_inbound_room(env, recipient);
         }
-        if withdraw_amount > treasury_bal {
-            panic!("insufficient treasury balance");
+        let cooldown = read_cooldown(env);
+        let unlock_ledger = env.ledger().sequence() + cooldown;
+        let id = next_withdrawal_id(env);
+
+        // The owed XLM leaves the staked pool now, so the exchange rate
+        // seen by remaining holders is unaffected by the queued exit.
+        write_i128(env, &DataKey::TotalXlmStaked, total_staked - xlm_to_return);
+        open_withdrawal(
+            env,
+            &WithdrawalRequest {
+                id,
+                user: recipient.clone(),
+                xlm_amount: xlm_to_return,
+                unlock_ledger,
+                claimed: false,
+            },
+        );
+        write_withdrawal_sxlm(env, id, sxlm_amount);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("delayed"),),
+            (user.clone(), xlm_to_return, id, unlock_ledger),
+        );
+    }
+
+    record_rate(env);
+
+    if user != recipient {
+        env.events().publish(
+            (soroban_sdk::symbol_short!("wd_to"),),
+            (user.clone(), recipient.clone(), xlm_to_return),
+        );
+    }
+
+    xlm_to_return
+}
+
+/// Take the protocol fee from `amount` and add the rest to the staked total,
+/// directly or through the vesting stream. `funded` says whether the reward
+/// XLM is already held by the contract.
+fn apply_rewards(env: &Env, amount: i128, funded: bool) {
+    if amount <= 0 {
+        panic!("reward amount must be positive");
+    }
+    require_operation(env, Operation::Rewards);
+    require_solvent(env);
+    accrue_rewards(env);
+
+    let fee = amount * read_protocol_fee_bps(env) / BPS_DENOMINATOR;
+    let net_reward = amount - fee;
+    distribute_fee(env, fee, funded);
+
+    let vesting_period = read_vesting_period(env);
+    if vesting_period == 0 {
+        let total_staked = read_i128(env, &DataKey::TotalXlmStaked);
+        write_i128(env, &DataKey::TotalXlmStaked, total_staked + net_reward);
+    } else {
+        let unvested = read_i128(env, &DataKey::UnvestedRewards) + net_reward;
+        let vesting_end = env.ledger().sequence() + vesting_period;
+        write_i128(env, &DataKey::UnvestedRewards, unvested);
+        env.storage().instance().set(&DataKey::RewardVestingEnd, &vesting_end);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("vesting"),),
+            (unvested, vesting_end),
+        );
+    }
+
+    record_rate(env);
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("rewards"),),
+        (amount, net_reward, fee),
+    );
+}
+
+fn build_reconcile_report(env: &Env) -> ReconcileReport {
+    let native_token_addr = read_native_token(env);
+    let token_balance = token::Client::new(env, &native_token_addr)
+        .balance(&env.current_contract_address());
+
+    let staked = read_i128(env, &DataKey::TotalXlmStaked) + read_i128(env, &DataKey::UnvestedRewards);
+    let pending_withdrawals = read_i128(env, &DataKey::PendingWithdrawalXlm);
+    let protocol_balances = read_i128(env, &DataKey::TreasuryBalance)
+        + read_i128(env, &DataKey::TotalFeeShares)
+        + read_i128(env, &DataKey::InsuranceFund);
+    let queued_rewards = read_i128(env, &DataKey::QueuedFundedRewards);
+    let liquidity_provided = read_i128(env, &DataKey::LiquidityProvided);
+
+    ReconcileReport {
+        token_balance,
+        staked,
+        pending_withdrawals,
+        protocol_balances,
+        queued_rewards,
+        liquidity_provided,
+        surplus: token_balance
+            - staked
+            - pending_withdrawals
+            - protocol_balances
+            - queued_rewards
+            - liquidity_provided,
+    }
+}
+
+/// Split a protocol fee between the insurance fund, the configured fee split
+/// recipients and the treasury, and emit the breakdown. The treasury cut is
+/// only swept out when `funded`; otherwise the XLM has not arrived yet and it
+/// accrues like any other balance.
+fn distribute_fee(env: &Env, fee: i128, funded: bool) {
+    let insurance_cut = fee * read_insurance_fee_bps(env) as i128 / BPS_DENOMINATOR;
+    if insurance_cut > 0 {
+        let fund = read_i128(env, &DataKey::InsuranceFund) + insurance_cut;
+        write_i128(env, &DataKey::InsuranceFund, fund);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("ins_in"),),
+            (insurance_cut, fund),
+        );
+    }
+
+    let distributable = fee - insurance_cut;
+    let mut split_total: i128 = 0;
+    for split in read_fee_splits(env).iter() {
+        let share = distributable * split.bps as i128 / BPS_DENOMINATOR;
+        if share == 0 {
+            continue;
         }
+        let key = DataKey::FeeShare(split.recipient.clone());
+        let balance: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+        env.storage().persistent().set(&key, &(balance + share));
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+        split_total += share;
 
-        let native_token_addr = read_native_token(&env);
-        let xlm_client = token::Client::new(&env, &native_token_addr);
-        xlm_client.transfer(&env.current_contract_address(), &admin, &withdraw_amount);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("fee_split"), split.recipient),
+            share,
+        );
+    }
+    if split_total > 0 {
+        let shares = read_i128(env, &DataKey::TotalFeeShares);
+        write_i128(env, &DataKey::TotalFeeShares, shares + split_total);
+    }
+
+    let treasury_cut = distributable - split_total;
+    let auto_sweep: bool = env
+        .storage()
+        .instance()
+        .get(&DataKey::AutoSweepFees)
+        .unwrap_or(false);
+    if auto_sweep && funded && treasury_cut > 0 {
+        let treasury = read_treasury(env);
+        let native_token_addr = read_native_token(env);
+        let xlm_client = token::Client::new(env, &native_token_addr);
+        xlm_client.transfer(&env.current_contract_address(), &treasury, &treasury_cut);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("fee_out"),),
+            (treasury, treasury_cut),
+        );
+    } else {
+        let treasury_bal = read_i128(env, &DataKey::TreasuryBalance);
+        write_i128(env, &DataKey::TreasuryBalance, treasury_bal + treasury_cut);
+    }
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("fees"),),
+        (fee, insurance_cut, split_total, treasury_cut),
+    );
+}
+
+/// Cover a slash from the insurance fund and take the rest out of the staked
+/// total. Returns (covered, uncovered).
+fn absorb_slash(env: &Env, slash_amount: i128) -> (i128, i128) {
+    let total_staked = read_i128(env, &DataKey::TotalXlmStaked);
+    if slash_amount > total_staked {
+        panic!("slash amount exceeds total staked");
+    }
+
+    let fund = read_i128(env, &DataKey::InsuranceFund);
+    let covered = slash_amount.min(fund);
+    let uncovered = slash_amount - covered;
+    write_i128(env, &DataKey::InsuranceFund, fund - covered);
+
+    let new_total = total_staked - uncovered;
+    write_i128(env, &DataKey::TotalXlmStaked, new_total);
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("slashed"),),
+        (slash_amount, new_total),
+    );
+    env.events().publish(
+        (soroban_sdk::symbol_short!("slash_cov"),),
+        (covered, uncovered, fund - covered),
+    );
+
+    let total_supply = read_i128(env, &DataKey::TotalSxlmSupply);
+    let new_rate = exchange_rate(new_total, total_supply);
+    record_rate(env);
+
+    if new_total == 0 && total_supply > 0 && !is_insolvent(env) {
+        env.storage().instance().set(&DataKey::Insolvent, &true);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("insolvent"),),
+            total_supply,
+        );
+    }
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("recalib"),),
+        (new_rate, new_total, total_supply),
+    );
+
+    (covered, uncovered)
+}
+
+fn require_slashable(env: &Env, validator: &Address, slash_amount: i128) {
+    if slash_amount > read_validator(env, validator).allocated {
+        panic!("slash amount exceeds validator allocation");
+    }
+}
+
+/// Charge a slash to `validator`'s allocation and absorb it.
+fn apply_validator_slash(env: &Env, validator: Address, slash_amount: i128) {
+    require_slashable(env, &validator, slash_amount);
+    let mut info = read_validator(env, &validator);
+    info.allocated -= slash_amount;
+    write_validator(env, &info);
+    let total_allocated = read_i128(env, &DataKey::TotalAllocated);
+    write_i128(env, &DataKey::TotalAllocated, total_allocated - slash_amount);
+
+    let (covered, uncovered) = absorb_slash(env, slash_amount);
+    env.events().publish(
+        (soroban_sdk::symbol_short!("val_slsh"), validator),
+        (slash_amount, covered, uncovered),
+    );
+}
+
+/// Move up to `limit` entries of the legacy single-entry queue map into
+/// per-request storage. Returns the number of legacy entries left.
+fn drain_legacy_queue(env: &Env, limit: u32) -> u32 {
+    let mut legacy: Map<u64, WithdrawalRequest> = match env
+        .storage()
+        .persistent()
+        .get(&DataKey::WithdrawalQueue)
+    {
+        Some(queue) => queue,
+        None => return 0,
+    };
+
+    let batch = legacy.keys().slice(0..legacy.len().min(clamp_page_size(limit)));
+    let mut moved_xlm: i128 = 0;
+    for id in batch.iter() {
+        let request = legacy.get_unchecked(id);
+        legacy.remove(id);
+        if request.claimed {
+            remove_user_withdrawal(env, &request.user, id);
+            continue;
+        }
+        // Entries written before the user index existed are not indexed yet.
+        let mut user_ids = read_user_withdrawals(env, &request.user);
+        if !user_ids.contains(id) {
+            user_ids.push_back(id);
+            write_user_withdrawals(env, &request.user, &user_ids);
+        }
+        write_withdrawal(env, &request);
+        moved_xlm += request.xlm_amount;
+    }
+
+    // Legacy requests were still counted in the staked total; move them
+    // over to the pending liability like new requests.
+    let total_staked = read_i128(env, &DataKey::TotalXlmStaked);
+    write_i128(env, &DataKey::TotalXlmStaked, total_staked - moved_xlm);
+    let pending = read_i128(env, &DataKey::PendingWithdrawalXlm);
+    write_i128(env, &DataKey::PendingWithdrawalXlm, pending + moved_xlm);
+
+    let remaining = legacy.len();
+    if remaining == 0 {
+        env.storage().persistent().remove(&DataKey::WithdrawalQueue);
+        env.storage().instance().set(&DataKey::QueueHead, &0u64);
+        advance_queue_head(env);
+    } else {
+        env.storage().persistent().set(&DataKey::WithdrawalQueue, &legacy);
+        env.storage().persistent().extend_ttl(
+            &DataKey::WithdrawalQueue,
+            PERSISTENT_LIFETIME_THRESHOLD,
+            PERSISTENT_BUMP_AMOUNT,
+        );
+    }
+
+    env.events().publish(
+        (soroban_sdk::symbol_short!("wq_migr"),),
+        (batch.len(), remaining),
+    );
+    remaining
+}
+
+/// Close every matured request in `ids` owned by `user`, emit a `claimed`
+/// event per request and pay the total in a single transfer.
+fn settle_matured(env: &Env, user: &Address, ids: &Vec<u64>) -> i128 {
+    require_operation(env, Operation::Claim);
+    let current_ledger = env.ledger().sequence();
+    let mut total: i128 = 0;
+
+    for id in ids.iter() {
+        let request = read_withdrawal(env, id).expect("withdrawal not found");
+        if request.user != *user {
+            panic!("not your withdrawal");
+        }
+        if current_ledger < request.unlock_ledger {
+            continue;
+        }
+
+        close_withdrawal(env, &request);
+        total += request.xlm_amount;
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("claimed"),),
+            (user.clone(), request.xlm_amount, id),
+        );
+    }
+
+    if total > 0 {
+        let native_token_addr = read_native_token(env);
+        let xlm_client = token::Client::new(env, &native_token_addr);
+        xlm_client.transfer(&env.current_contract_address(), user, &total);
+    }
+    total
+}
+
+#[contract]
+pub struct StakingContract;
+
+#[contractimpl]
+impl StakingContract {
+    /// Initialize the staking contract.
+    pub fn initialize(
+        env: Env,
+        admin: Address,
+        sxlm_token: Address,
+        native_token: Address,
+        cooldown_period: u32,
+    ) {
+        if env.storage().instance().has(&DataKey::Initialized) {
+            panic!("already initialized");
+        }
+        env.storage().instance().set(&DataKey::Initialized, &true);
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        env.storage().instance().set(&DataKey::SxlmToken, &sxlm_token);
+        env.storage().instance().set(&DataKey::NativeToken, &native_token);
+        env.storage().instance().set(&DataKey::CooldownPeriod, &cooldown_period);
+        env.storage().instance().set(&DataKey::Paused, &false);
+        env.storage().instance().set(&DataKey::Treasury, &admin);
+        write_i128(&env, &DataKey::ProtocolFeeBps, DEFAULT_PROTOCOL_FEE_BPS);
+        write_i128(&env, &DataKey::TotalXlmStaked, 0);
+        write_i128(&env, &DataKey::TotalSxlmSupply, 0);
+        write_i128(&env, &DataKey::LiquidityBuffer, 0);
+        write_i128(&env, &DataKey::TreasuryBalance, 0);
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        extend_instance(&env);
+    }
+
+    /// Announce a new staking WASM, installable from `eta_ledger`. Scheduled
+    /// by the Upgrader role; holders get at least `upgrade::MIN_UPGRADE_DELAY`
+    /// ledgers to exit first.
+    pub fn schedule_upgrade(env: Env, new_wasm_hash: BytesN<32>, eta_ledger: u32) {
+        require_role(&env, Role::Upgrader);
+        extend_instance(&env);
+        upgrade::schedule(&env, new_wasm_hash, eta_ledger);
+    }
+
+    /// Install the scheduled upgrade once its eta has passed. Callable by anyone.
+    pub fn execute_upgrade(env: Env) {
+        upgrade::execute(&env);
+    }
+
+    pub fn cancel_upgrade(env: Env) {
+        require_role(&env, Role::Upgrader);
+        extend_instance(&env);
+        upgrade::cancel(&env);
+    }
+
+    pub fn pending_upgrade(env: Env) -> Option<PendingUpgrade> {
+        extend_instance(&env);
+        upgrade::pending(&env)
+    }
+
+    /// Bring storage written by an older WASM up to `SCHEMA_VERSION`.
+    /// `from_version` must match `version()`, so a migration cannot be applied
+    /// twice or out of order. Returns the version reached.
+    pub fn migrate(env: Env, from_version: u32) -> u32 {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        let version = read_schema_version(&env);
+        if from_version != version {
+            panic!("schema version mismatch");
+        }
+        if version >= SCHEMA_VERSION {
+            panic!("already at latest schema version");
+        }
+
+        // 0 -> 1: move the legacy withdrawal queue into per-request entries, a
+        // batch per call, then seed the rate history from the current rate.
+        if drain_legacy_queue(&env, MAX_PAGE_SIZE) > 0 {
+            return version;
+        }
+        accrue_rewards(&env);
+        record_rate(&env);
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("migrated"),),
+            (from_version, SCHEMA_VERSION),
+        );
+        SCHEMA_VERSION
+    }
+
+    /// Storage layout version of this deployment.
+    pub fn version(env: Env) -> u32 {
+        extend_instance(&env);
+        read_schema_version(&env)
+    }
+
+    /// Bump instance TTL — can be called by anyone to keep the contract alive.
+    pub fn bump_instance(env: Env) {
+        extend_instance(&env);
+    }
+
+    /// Bump an open withdrawal request's TTL — can be called by anyone.
+    pub fn bump_withdrawal(env: Env, withdrawal_id: u64) {
+        extend_instance(&env);
+        read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+    }
+
+    /// Move up to `limit` requests out of the legacy single-entry queue map into
+    /// per-request storage, dropping claimed ones. Anyone can call this until it
+    /// returns 0 (the number of legacy entries left).
+    pub fn migrate_withdrawal_queue(env: Env, limit: u32) -> u32 {
+        extend_instance(&env);
+        drain_legacy_queue(&env, limit)
+    }
+
+    // ==========================================================
+    // Core staking functions
+    // ==========================================================
+
+    /// Deposit XLM and receive sXLM tokens.
+    pub fn deposit(env: Env, user: Address, xlm_amount: i128) {
+        require_not_paused(&env);
+        user.require_auth();
+        extend_instance(&env);
+        execute_deposit(&env, &user, &user, xlm_amount, 0);
+    }
+
+    /// Deposit XLM from `payer` and mint the sXLM to `beneficiary`. Only the
+    /// payer signs. Returns the sXLM minted.
+    pub fn deposit_for(env: Env, payer: Address, beneficiary: Address, xlm_amount: i128) -> i128 {
+        require_not_paused(&env);
+        payer.require_auth();
+        extend_instance(&env);
+        execute_deposit(&env, &payer, &beneficiary, xlm_amount, 0)
+    }
+
+    /// Deposit XLM, aborting if fewer than `min_sxlm_out` sXLM would be minted
+    /// or the transaction lands after `deadline_ledger`. Returns the sXLM minted.
+    pub fn deposit_with_limits(
+        env: Env,
+        user: Address,
+        xlm_amount: i128,
+        min_sxlm_out: i128,
+        deadline_ledger: u32,
+    ) -> i128 {
+        require_not_paused(&env);
+        user.require_auth();
+        require_before_deadline(&env, deadline_ledger);
+        extend_instance(&env);
+        execute_deposit(&env, &user, &user, xlm_amount, min_sxlm_out)
+    }
+
+    /// Request withdrawal: burns sXLM and returns XLM.
+    pub fn request_withdrawal(env: Env, user: Address, sxlm_amount: i128) {
+        require_not_paused(&env);
+        user.require_auth();
+        extend_instance(&env);
+        execute_withdrawal(&env, &user, &user, sxlm_amount, 0);
+    }
+
+    /// Burn `owner`'s sXLM and send the proceeds to `recipient`: paid directly
+    /// when the buffer covers it, otherwise as a withdrawal request owned by
+    /// `recipient`, which is refused once the recipient holds
+    /// `MAX_INBOUND_WITHDRAWALS` open requests. Only the owner signs. Returns
+    /// the XLM owed.
+    pub fn request_withdrawal_to(
+        env: Env,
+        owner: Address,
+        recipient: Address,
+        sxlm_amount: i128,
+    ) -> i128 {
+        require_not_paused(&env);
+        owner.require_auth();
+        extend_instance(&env);
+        execute_withdrawal(&env, &owner, &recipient, sxlm_amount, 0)
+    }
+
+    /// Request withdrawal, aborting if less than `min_xlm_out` XLM would be
+    /// returned or the transaction lands after `deadline_ledger`. Returns the
+    /// XLM paid out or queued.
+    pub fn request_withdrawal_with_limits(
+        env: Env,
+        user: Address,
+        sxlm_amount: i128,
+        min_xlm_out: i128,
+        deadline_ledger: u32,
+    ) -> i128 {
+        require_not_paused(&env);
+        user.require_auth();
+        require_before_deadline(&env, deadline_ledger);
+        extend_instance(&env);
+        execute_withdrawal(&env, &user, &user, sxlm_amount, min_xlm_out)
+    }
+    /// Claim a delayed withdrawal after cooldown has expired. The request is
+    /// pruned from storage once paid.
+    pub fn claim_withdrawal(env: Env, user: Address, withdrawal_id: u64) {
+        user.require_auth();
+        extend_instance(&env);
+        require_operation(&env, Operation::Claim);
+
+        let request = read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+
+        if request.user != user {
+            panic!("not your withdrawal");
+        }
+        if env.ledger().sequence() < request.unlock_ledger {
+            panic!("cooldown not expired");
+        }
+
+        close_withdrawal(&env, &request);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&env.current_contract_address(), &user, &request.xlm_amount);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("claimed"),),
+            (user, request.xlm_amount, withdrawal_id),
+        );
+    }
+
+    /// Cancel an open withdrawal and re-mint sXLM for its XLM at the current
+    /// exchange rate, returning the owner to the staked position. Never mints
+    /// more than the request burned, so queuing cannot sidestep a slash that
+    /// lands in the meantime. Gated like a deposit. Returns the sXLM minted.
+    pub fn cancel_withdrawal(env: Env, user: Address, withdrawal_id: u64) -> i128 {
+        require_operation(&env, Operation::Deposit);
+        user.require_auth();
+        extend_instance(&env);
+
+        let request = read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+        if request.user != user {
+            panic!("not your withdrawal");
+        }
+
+        require_solvent(&env);
+        require_no_queued_slash(&env);
+        accrue_rewards(&env);
+        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
+        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+        let mut sxlm_to_mint = sxlm_for_xlm(request.xlm_amount, total_staked, total_supply);
+        if let Some(burned) = read_withdrawal_sxlm(&env, withdrawal_id) {
+            sxlm_to_mint = sxlm_to_mint.min(burned);
+        }
+        if sxlm_to_mint <= 0 {
+            panic!("mint amount too small");
+        }
+
+        close_withdrawal(&env, &request);
+        write_i128(&env, &DataKey::TotalXlmStaked, total_staked + request.xlm_amount);
+        write_i128(&env, &DataKey::TotalSxlmSupply, total_supply + sxlm_to_mint);
+        record_rate(&env);
+
+        let sxlm_token = read_sxlm_token(&env);
+        let sxlm_client = SxlmTokenClient::new(&env, &sxlm_token);
+        sxlm_client.mint(&user, &sxlm_to_mint);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("wd_cncl"),),
+            (user, withdrawal_id, request.xlm_amount, sxlm_to_mint),
+        );
+
+        sxlm_to_mint
+    }
+
+    // ==========================================================
+    // Withdrawal request transfers
+    // ==========================================================
+
+    /// Transfer the claim right of an open withdrawal to `to`.
+    pub fn transfer_withdrawal(env: Env, from: Address, to: Address, withdrawal_id: u64) {
+        from.require_auth();
+        extend_instance(&env);
+
+        let request = read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+        if request.user != from {
+            panic!("not your withdrawal");
+        }
+        move_withdrawal(&env, request, &to);
+    }
+
+    /// Allow `spender` to transfer one of `owner`'s open withdrawals. Replaces
+    /// any previous approval for that request.
+    pub fn approve_withdrawal(env: Env, owner: Address, spender: Address, withdrawal_id: u64) {
+        owner.require_auth();
+        extend_instance(&env);
+
+        let request = read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+        if request.user != owner {
+            panic!("not your withdrawal");
+        }
+
+        let key = DataKey::WithdrawalApproval(withdrawal_id);
+        env.storage().persistent().set(&key, &spender);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("wd_appr"),),
+            (owner, spender, withdrawal_id),
+        );
+    }
+
+    /// Drop the approval on one of `owner`'s open withdrawals.
+    pub fn revoke_withdrawal_approval(env: Env, owner: Address, withdrawal_id: u64) {
+        owner.require_auth();
+        extend_instance(&env);
+
+        let request = read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+        if request.user != owner {
+            panic!("not your withdrawal");
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::WithdrawalApproval(withdrawal_id));
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("wd_rvk"),),
+            (owner, withdrawal_id),
+        );
+    }
+
+    /// Transfer an open withdrawal on the owner's behalf using an approval.
+    /// The approval is consumed.
+    pub fn transfer_withdrawal_from(
+        env: Env,
+        spender: Address,
+        from: Address,
+        to: Address,
+        withdrawal_id: u64,
+    ) {
+        spender.require_auth();
+        extend_instance(&env);
+
+        let request = read_withdrawal(&env, withdrawal_id).expect("withdrawal not found");
+        if request.user != from {
+            panic!("not your withdrawal");
+        }
+        if read_withdrawal_approval(&env, withdrawal_id) != Some(spender) {
+            panic!("not approved");
+        }
+        move_withdrawal(&env, request, &to);
+    }
+
+    /// Claim several withdrawals in one transfer. Requests still in cooldown are
+    /// skipped; unknown ids or requests owned by someone else abort the call.
+    /// Returns the total XLM paid out.
+    pub fn claim_withdrawals(env: Env, user: Address, withdrawal_ids: Vec<u64>) -> i128 {
+        user.require_auth();
+        extend_instance(&env);
+        if withdrawal_ids.len() > MAX_PAGE_SIZE {
+            panic!("too many withdrawal ids");
+        }
+        settle_matured(&env, &user, &withdrawal_ids)
+    }
+
+    /// Claim every matured withdrawal among `MAX_PAGE_SIZE` open requests of
+    /// `user`, starting at position `start` of their index as paged by
+    /// `get_user_withdrawals`. Returns the total XLM paid out. Claimed requests
+    /// leave the index, so later requests move down to fill their places.
+    pub fn claim_all_matured(env: Env, user: Address, start: u32) -> i128 {
+        user.require_auth();
+        extend_instance(&env);
+        let ids = read_user_withdrawals(&env, &user);
+        let end = ids.len().min(start.saturating_add(MAX_PAGE_SIZE));
+        let batch = ids.slice(start.min(end)..end);
+        settle_matured(&env, &user, &batch)
+    }
+
+    // ==========================================================
+    // Reward & Fee functions
+    // ==========================================================
+
+    /// Add staking rewards — takes the protocol fee, remainder increases
+    /// total_xlm_staked, raising the exchange rate. With a vesting period set,
+    /// the remainder (plus anything still unvested) streams in linearly over
+    /// that many ledgers instead. The reward XLM is expected to reach the
+    /// contract separately; see `add_rewards_funded`.
+    pub fn add_rewards(env: Env, amount: i128) {
+        require_role(&env, Role::Rewarder);
+        require_unfunded_rewards_allowed(&env);
+        require_operation(&env, Operation::Rewards);
+        if amount <= 0 {
+            panic!("reward amount must be positive");
+        }
+        extend_instance(&env);
+        accrue_rewards(&env);
+
+        if rate_change_allowed(&env, staked_after_reward(&env, amount)) {
+            apply_rewards(&env, amount, false);
+        } else {
+            queue_rate_change(&env, RateChangeKind::Reward, amount);
+        }
+    }
+
+    /// Like `add_rewards`, but pulls the reward XLM from the rewarder in the
+    /// same call so the books cannot run ahead of the balance.
+    pub fn add_rewards_funded(env: Env, amount: i128) {
+        let rewarder = require_role(&env, Role::Rewarder);
+        if amount <= 0 {
+            panic!("reward amount must be positive");
+        }
+        require_operation(&env, Operation::Rewards);
+        extend_instance(&env);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&rewarder, &env.current_contract_address(), &amount);
+
+        accrue_rewards(&env);
+        if rate_change_allowed(&env, staked_after_reward(&env, amount)) {
+            apply_rewards(&env, amount, true);
+        } else {
+            queue_rate_change(&env, RateChangeKind::FundedReward(rewarder), amount);
+            let queued = read_i128(&env, &DataKey::QueuedFundedRewards);
+            write_i128(&env, &DataKey::QueuedFundedRewards, queued + amount);
+        }
+    }
+
+    /// When enabled, unfunded `add_rewards` calls are rejected.
+    pub fn set_require_funded_rewards(env: Env, required: bool) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::RequireFundedRewards, &required);
+    }
+
+    /// Set the protocol fee taken from rewards, up to `MAX_PROTOCOL_FEE_BPS`.
+    pub fn set_protocol_fee_bps(env: Env, bps: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        if bps as i128 > MAX_PROTOCOL_FEE_BPS {
+            panic!("protocol fee exceeds maximum");
+        }
+        extend_instance(&env);
+        write_i128(&env, &DataKey::ProtocolFeeBps, bps as i128);
+        env.events().publish((soroban_sdk::symbol_short!("fee_upd"),), bps);
+    }
+
+    /// Replace the fee split recipients. Shares are in bps of the fee left after
+    /// the insurance cut and may not add up to more than 100%; the remainder
+    /// goes to the treasury. Pass an empty list to send everything there.
+    pub fn set_fee_splits(env: Env, splits: Vec<FeeSplit>) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        if splits.len() > MAX_FEE_SPLITS {
+            panic!("too many fee splits");
+        }
+        let mut total: i128 = 0;
+        for split in splits.iter() {
+            total += split.bps as i128;
+        }
+        if total > BPS_DENOMINATOR {
+            panic!("fee splits exceed 100%");
+        }
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::FeeSplits, &splits);
+        env.events().publish((soroban_sdk::symbol_short!("split_upd"),), splits);
+    }
+
+    /// Pay out `recipient`'s accumulated fee split balance. Returns the amount.
+    pub fn claim_fee_share(env: Env, recipient: Address) -> i128 {
+        recipient.require_auth();
+        extend_instance(&env);
+
+        let key = DataKey::FeeShare(recipient.clone());
+        let balance: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+        if balance <= 0 {
+            panic!("no fees to withdraw");
+        }
+        env.storage().persistent().remove(&key);
+        let shares = read_i128(&env, &DataKey::TotalFeeShares);
+        write_i128(&env, &DataKey::TotalFeeShares, shares - balance);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&env.current_contract_address(), &recipient, &balance);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("fee_out"),),
+            (recipient, balance),
+        );
+        balance
+    }
+
+    /// Set the number of ledgers over which new rewards vest (0 = immediate).
+    /// Rewards already streaming keep their current end ledger.
+    pub fn set_reward_vesting_period(env: Env, ledgers: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        accrue_rewards(&env);
+        env.storage().instance().set(&DataKey::RewardVestingPeriod, &ledgers);
+        env.events().publish((soroban_sdk::symbol_short!("vest_upd"),), ledgers);
+    }
+
+    /// Withdraw protocol fees to the treasury address.
+    /// If amount > 0, withdraw that specific amount; if 0, withdraw all.
+    pub fn withdraw_fees(env: Env, amount: i128) {
+        require_role(&env, Role::Treasurer);
+        extend_instance(&env);
+
+        let treasury_bal = read_i128(&env, &DataKey::TreasuryBalance);
+
+        let withdraw_amount = if amount <= 0 {
+            treasury_bal
+        } else {
+            amount
+        };
+
+        if withdraw_amount <= 0 {
+            panic!("no fees to withdraw");
+        }
+        if withdraw_amount > treasury_bal {
+            panic!("insufficient treasury balance");
+        }
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        let treasury = read_treasury(&env);
+        xlm_client.transfer(&env.current_contract_address(), &treasury, &withdraw_amount);
+
+        write_i128(&env, &DataKey::TreasuryBalance, treasury_bal - withdraw_amount);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("fee_out"),),
+            (treasury, withdraw_amount),
+        );
+    }
+
+    pub fn set_treasury(env: Env, treasury: Address) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        let previous = read_treasury(&env);
+        env.storage().instance().set(&DataKey::Treasury, &treasury);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("treas_upd"),),
+            (previous, treasury),
+        );
+    }
+
+    /// When enabled, funded rewards pay the treasury's fee cut out immediately
+    /// instead of accruing it in the treasury balance. Fees on unfunded
+    /// `add_rewards` calls always accrue, since their XLM has not arrived.
+    pub fn set_auto_sweep_fees(env: Env, enabled: bool) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::AutoSweepFees, &enabled);
+        env.events().publish((soroban_sdk::symbol_short!("sweep_upd"),), enabled);
+    }
+
+    // ==========================================================
+    // Slashing
+    // ==========================================================
+
+    /// Apply a slash not attributed to a validator. The insurance fund absorbs
+    /// it first; only the remainder lowers the exchange rate. A slash over the
+    /// rate limits is queued, and deposits and exits wait until it is applied
+    /// or vetoed.
+    pub fn apply_slashing(env: Env, slash_amount: i128) {
+        require_role(&env, Role::Slasher);
+        if slash_amount <= 0 {
+            panic!("slash amount must be positive");
+        }
+        extend_instance(&env);
+        accrue_rewards(&env);
+
+        if rate_change_allowed(&env, staked_after_slash(&env, slash_amount)) {
+            absorb_slash(&env, slash_amount);
+        } else {
+            queue_rate_change(&env, RateChangeKind::Slash, slash_amount);
+            write_queued_slashes(&env, read_queued_slashes(&env) + 1);
+        }
+    }
+
+    /// Apply a slash caused by `validator`: its recorded allocation drops by
+    /// the slashed amount, and the loss is covered like `apply_slashing`.
+    pub fn slash_validator(env: Env, validator: Address, slash_amount: i128) {
+        require_role(&env, Role::Slasher);
+        if slash_amount <= 0 {
+            panic!("slash amount must be positive");
+        }
+        extend_instance(&env);
+        accrue_rewards(&env);
+
+        if rate_change_allowed(&env, staked_after_slash(&env, slash_amount)) {
+            apply_validator_slash(&env, validator, slash_amount);
+        } else {
+            require_slashable(&env, &validator, slash_amount);
+            queue_rate_change(&env, RateChangeKind::ValidatorSlash(validator), slash_amount);
+            write_queued_slashes(&env, read_queued_slashes(&env) + 1);
+        }
+    }
+
+    /// Top up the insurance fund with XLM from `from`.
+    pub fn fund_insurance(env: Env, from: Address, amount: i128) {
+        from.require_auth();
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        extend_instance(&env);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&from, &env.current_contract_address(), &amount);
+
+        let fund = read_i128(&env, &DataKey::InsuranceFund) + amount;
+        write_i128(&env, &DataKey::InsuranceFund, fund);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("ins_in"),),
+            (amount, fund),
+        );
+    }
+
+    /// Put `amount` XLM from `from` back behind outstanding sXLM, without
+    /// minting, and leave insolvency mode. Holders then redeem their sXLM pro
+    /// rata against the recapitalized stake; queued withdrawals were already
+    /// set aside before the slash and are unaffected throughout.
+    pub fn recapitalize(env: Env, from: Address, amount: i128) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        from.require_auth();
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        extend_instance(&env);
+        accrue_rewards(&env);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&from, &env.current_contract_address(), &amount);
+
+        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked) + amount;
+        write_i128(&env, &DataKey::TotalXlmStaked, total_staked);
+        env.storage().instance().set(&DataKey::Insolvent, &false);
+        record_rate(&env);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("recap"),),
+            (from, amount, total_staked),
+        );
+    }
+
+    /// Set the share of each protocol fee (in bps of the fee) that goes to the
+    /// insurance fund instead of the treasury.
+    pub fn set_insurance_fee_bps(env: Env, bps: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        if bps as i128 > BPS_DENOMINATOR {
+            panic!("invalid insurance share");
+        }
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::InsuranceFeeBps, &bps);
+        env.events().publish((soroban_sdk::symbol_short!("ins_upd"),), bps);
+    }
+
+    /// Compare the contract's XLM balance with its accounted liabilities and
+    /// publish the result. Callable by anyone, e.g. a monitoring keeper.
+    pub fn reconcile(env: Env) -> ReconcileReport {
+        extend_instance(&env);
+        accrue_rewards(&env);
+        let report = build_reconcile_report(&env);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("reconcile"),),
+            (report.token_balance, report.surplus),
+        );
+        report
+    }
+
+    pub fn recalibrate_rate(env: Env) -> i128 {
+        extend_instance(&env);
+        accrue_rewards(&env);
+        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
+        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+
+        let new_rate = exchange_rate(total_staked, total_supply);
+        record_rate(&env);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("recalib"),),
+            (new_rate, total_staked, total_supply),
+        );
+
+        new_rate
+    }
+
+    // ==========================================================
+    // Rate change limits
+    // ==========================================================
+
+    pub fn set_rate_limits(env: Env, limits: RateLimits) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        if limits.per_call_bps as i128 > BPS_DENOMINATOR
+            || limits.window_bps as i128 > BPS_DENOMINATOR
+        {
+            panic!("invalid rate limit");
+        }
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::RateLimits, &limits);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("rlim_upd"),),
+            (limits.per_call_bps, limits.window_bps, limits.window_ledgers, limits.delay_ledgers),
+        );
+    }
+
+    /// Apply a queued reward or slash once its timelock has passed, without
+    /// checking it against the rate limits again. Callable by anyone.
+    pub fn execute_rate_change(env: Env, id: u64) {
+        extend_instance(&env);
+        let change = read_pending_rate_change(&env, id);
+        if env.ledger().sequence() < change.eta {
+            panic!("timelock not expired");
+        }
+        env.storage()
+            .persistent()
+            .remove(&DataKey::PendingRateChange(id));
+        accrue_rewards(&env);
+
+        match change.kind {
+            RateChangeKind::Reward => {
+                require_unfunded_rewards_allowed(&env);
+                apply_rewards(&env, change.amount, false);
+            }
+            RateChangeKind::FundedReward(_) => {
+                let queued = read_i128(&env, &DataKey::QueuedFundedRewards);
+                write_i128(&env, &DataKey::QueuedFundedRewards, queued - change.amount);
+                apply_rewards(&env, change.amount, true);
+            }
+            RateChangeKind::Slash => {
+                write_queued_slashes(&env, read_queued_slashes(&env) - 1);
+                absorb_slash(&env, change.amount);
+            }
+            RateChangeKind::ValidatorSlash(validator) => {
+                write_queued_slashes(&env, read_queued_slashes(&env) - 1);
+                apply_validator_slash(&env, validator, change.amount);
+            }
+        }
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("rate_exe"),),
+            (id, change.amount),
+        );
+    }
+
+    /// Drop a queued reward or slash. Funded rewards go back to their funder.
+    pub fn veto_rate_change(env: Env, id: u64) {
+        require_role(&env, Role::Guardian);
+        extend_instance(&env);
+        let change = read_pending_rate_change(&env, id);
+        env.storage()
+            .persistent()
+            .remove(&DataKey::PendingRateChange(id));
+
+        match change.kind {
+            RateChangeKind::FundedReward(funder) => {
+                let queued = read_i128(&env, &DataKey::QueuedFundedRewards);
+                write_i128(&env, &DataKey::QueuedFundedRewards, queued - change.amount);
+                let native_token_addr = read_native_token(&env);
+                let xlm_client = token::Client::new(&env, &native_token_addr);
+                xlm_client.transfer(&env.current_contract_address(), &funder, &change.amount);
+            }
+            RateChangeKind::Slash | RateChangeKind::ValidatorSlash(_) => {
+                write_queued_slashes(&env, read_queued_slashes(&env) - 1);
+            }
+            RateChangeKind::Reward => {}
+        }
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("rate_veto"),),
+            (id, change.amount),
+        );
+    }
+
+    // ==========================================================
+    // Emergency pause
+    // ==========================================================
+
+    pub fn pause(env: Env) {
+        require_role(&env, Role::Guardian);
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::Paused, &true);
+        env.events().publish((soroban_sdk::symbol_short!("paused"),), true);
+    }
+
+    /// Lift the global pause. Admin only, so a compromised guardian key can
+    /// stop the protocol but not restart it.
+    pub fn unpause(env: Env) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::Paused, &false);
+        env.events().publish((soroban_sdk::symbol_short!("paused"),), false);
+    }
+
+    /// Pause a single operation, leaving the others running.
+    pub fn pause_operation(env: Env, operation: Operation) {
+        require_role(&env, Role::Guardian);
+        extend_instance(&env);
+        env.storage()
+            .instance()
+            .set(&DataKey::OperationPaused(operation), &true);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("op_paused"), operation),
+            true,
+        );
+    }
+
+    /// Resume a single operation. Admin only, like `unpause`.
+    pub fn unpause_operation(env: Env, operation: Operation) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        env.storage()
+            .instance()
+            .remove(&DataKey::OperationPaused(operation));
+        env.events().publish(
+            (soroban_sdk::symbol_short!("op_paused"), operation),
+            false,
+        );
+    }
+
+    // ==========================================================
+    // Liquidity & Validators
+    // ==========================================================
+
+    pub fn add_liquidity(env: Env, amount: i128) {
+        let treasurer = require_role(&env, Role::Treasurer);
+        if amount <= 0 {
+            panic!("liquidity amount must be positive");
+        }
+        extend_instance(&env);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&treasurer, &env.current_contract_address(), &amount);
+
+        let buffer = read_i128(&env, &DataKey::LiquidityBuffer);
+        write_i128(&env, &DataKey::LiquidityBuffer, buffer + amount);
+        let provided = read_i128(&env, &DataKey::LiquidityProvided);
+        write_i128(&env, &DataKey::LiquidityProvided, provided + amount);
+        let contribution = read_buffer_contribution(&env, &treasurer);
+        write_buffer_contribution(&env, &treasurer, contribution + amount);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("liq_add"),),
+            (treasurer, amount),
+        );
+    }
+
+    /// Return `amount` of a provider's buffer contribution to them. The buffer
+    /// left afterwards must still cover every queued withdrawal plus the
+    /// target buffer.
+    pub fn remove_liquidity(env: Env, provider: Address, amount: i128) {
+        require_role(&env, Role::Treasurer);
+        if amount <= 0 {
+            panic!("liquidity amount must be positive");
+        }
+        extend_instance(&env);
+
+        let contribution = read_buffer_contribution(&env, &provider);
+        if amount > contribution {
+            panic!("exceeds provider contribution");
+        }
+        let buffer = read_i128(&env, &DataKey::LiquidityBuffer);
+        if amount > buffer {
+            panic!("insufficient buffer");
+        }
+        accrue_rewards(&env);
+        let required = read_i128(&env, &DataKey::PendingWithdrawalXlm)
+            + target_buffer(&env, read_i128(&env, &DataKey::TotalXlmStaked));
+        if buffer - amount < required {
+            panic!("would leave withdrawals uncovered");
+        }
+
+        write_i128(&env, &DataKey::LiquidityBuffer, buffer - amount);
+        let provided = read_i128(&env, &DataKey::LiquidityProvided);
+        write_i128(&env, &DataKey::LiquidityProvided, provided - amount);
+        write_buffer_contribution(&env, &provider, contribution - amount);
+
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+        xlm_client.transfer(&env.current_contract_address(), &provider, &amount);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("liq_rm"),),
+            (provider, amount),
+        );
+    }
+
+    /// Set the buffer size deposits refill, in bps of the staked total.
+    pub fn set_target_buffer_bps(env: Env, bps: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        if bps as i128 > BPS_DENOMINATOR {
+            panic!("invalid buffer target");
+        }
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::TargetBufferBps, &bps);
+        env.events().publish((soroban_sdk::symbol_short!("buf_tgt"),), bps);
+    }
+
+    /// Settle queued withdrawals early, oldest first, while the buffer stays
+    /// at or above its target after paying them. Stops at the first request
+    /// that does not fit so later requests never overtake earlier ones.
+    /// Looks at no more than `max` ids, pruned ones included, and moves the
+    /// queue head past everything it settles or skips, so repeated calls keep
+    /// making progress. Callable by anyone. Returns the number of requests
+    /// settled.
+    pub fn process_withdrawal_queue(env: Env, max: u32) -> u32 {
+        require_operation(&env, Operation::Claim);
+        extend_instance(&env);
+        accrue_rewards(&env);
+
+        let target = target_buffer(&env, read_i128(&env, &DataKey::TotalXlmStaked));
+        let mut buffer = read_i128(&env, &DataKey::LiquidityBuffer);
+        let native_token_addr = read_native_token(&env);
+        let xlm_client = token::Client::new(&env, &native_token_addr);
+
+        let count = withdrawal_counter(&env);
+        let start = read_queue_head(&env);
+        let end = count.min(start + clamp_page_size(max) as u64);
+        let mut head = start;
+        let mut settled = 0u32;
+        for id in start..end {
+            // Ids claimed or cancelled out of order are already pruned.
+            if let Some(request) = read_withdrawal(&env, id) {
+                if buffer - request.xlm_amount < target {
+                    break;
+                }
+                buffer -= request.xlm_amount;
+                write_i128(&env, &DataKey::LiquidityBuffer, buffer);
+                close_withdrawal(&env, &request);
+                xlm_client.transfer(
+                    &env.current_contract_address(),
+                    &request.user,
+                    &request.xlm_amount,
+                );
+
+                env.events().publish(
+                    (soroban_sdk::symbol_short!("early"),),
+                    (request.user, request.xlm_amount, id),
+                );
+                settled += 1;
+            }
+            head = id + 1;
+        }
+        // Closing the head request may already have moved it further.
+        if head > read_queue_head(&env) {
+            env.storage().instance().set(&DataKey::QueueHead, &head);
+        }
+        settled
+    }
+
+    /// Replace the validator set. Validators no longer listed are removed (they
+    /// must have no allocated stake), new ones are registered with zero
+    /// commission, and every listed validator gets an equal target weight.
+    pub fn update_validators(env: Env, validators: Vec<Address>) {
+        require_role(&env, Role::ValidatorManager);
+        extend_instance(&env);
+
+        for existing in read_validator_list(&env).iter() {
+            if !validators.contains(&existing) {
+                unregister_validator(&env, &existing);
+            }
+        }
+
+        let count = validators.len();
+        for (i, validator) in validators.iter().enumerate() {
+            // Spread the rounding remainder over the first validators.
+            let mut weight = BPS_DENOMINATOR as u32 / count;
+            if (i as u32) < BPS_DENOMINATOR as u32 % count {
+                weight += 1;
+            }
+            if read_validator_list(&env).contains(&validator) {
+                let mut info = read_validator(&env, &validator);
+                info.target_weight_bps = weight;
+                info.active = true;
+                write_validator(&env, &info);
+            } else {
+                register_validator(
+                    &env,
+                    &ValidatorInfo {
+                        address: validator,
+                        commission_bps: 0,
+                        target_weight_bps: weight,
+                        active: true,
+                        allocated: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Hand `role` to `account`, replacing its current holder.
+    pub fn grant_role(env: Env, role: Role, account: Address) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        env.storage()
+            .instance()
+            .set(&DataKey::RoleHolder(role), &account);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("role_set"), role),
+            account,
+        );
+    }
+
+    /// Take `role` back to the admin.
+    pub fn revoke_role(env: Env, role: Role) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        let previous = read_role_holder(&env, role);
+        env.storage()
+            .instance()
+            .remove(&DataKey::RoleHolder(role));
+        env.events().publish(
+            (soroban_sdk::symbol_short!("role_rvk"), role),
+            previous,
+        );
+    }
+
+    /// Propose `new_admin`. Every role without a holder of its own moves with
+    /// the admin, so the successor takes those over too once they call
+    /// `accept_admin`.
+    pub fn propose_admin(env: Env, new_admin: Address) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::propose(&env, admin, new_admin);
+    }
+
+    /// Complete the handover. Only the proposed admin can call this.
+    pub fn accept_admin(env: Env) {
+        extend_instance(&env);
+        let new_admin = handover::accept(&env, read_admin(&env));
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+    }
+
+    pub fn cancel_admin_transfer(env: Env) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::cancel(&env);
+    }
+
+    pub fn pending_admin(env: Env) -> Option<Address> {
+        extend_instance(&env);
+        handover::pending(&env)
+    }
+
+    pub fn set_cooldown_period(env: Env, new_cooldown: u32) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        env.storage().instance().set(&DataKey::CooldownPeriod, &new_cooldown);
+        env.events().publish((soroban_sdk::symbol_short!("cd_upd"),), new_cooldown);
+    }
+
+    // ==========================================================
+    // Validator registry
+    // ==========================================================
+
+    /// Register a validator. Active target weights may not exceed 100% in total.
+    pub fn add_validator(
+        env: Env,
+        validator: Address,
+        commission_bps: u32,
+        target_weight_bps: u32,
+    ) {
+        require_role(&env, Role::ValidatorManager);
+        extend_instance(&env);
+        register_validator(
+            &env,
+            &ValidatorInfo {
+                address: validator.clone(),
+                commission_bps,
+                target_weight_bps,
+                active: true,
+                allocated: 0,
+            },
+        );
+        require_valid_weights(&env);
+
+        env.events().publish(
+            (soroban_sdk::symbol_short!("val_add"),),
+            validator,
+        );
+    }
+
+    /// Remove a validator. Its allocation must have been moved away first.
+    pub fn remove_validator(env: Env, validator: Address) {
+        require_role(&env, Role::ValidatorManager);
+        extend_instance(&env);
+        unregister_validator(&env, &validator);
+    }
+
+    pub fn set_validator_weight(env: Env, validator: Address, target_weight_bps: u32) {
+        require_role(&env, Role::ValidatorManager);
+        extend_instance(&env);
+        let mut info = read_validator(&env, &validator);
+        info.target_weight_bps = target_weight_bps;
+        write_validator(&env, &info);
+        require_valid_weights(&env);
+    }
+
+    pub fn set_validator_commission(env: Env, validator: Address, commission_bps: u32) {
+        require_role(&env, Role::ValidatorManager);
+        if commission_bps as i128 > BPS_DENOMINATOR {
+            panic!("invalid commission");
+        }
+        extend_instance(&env);
+        let mut info = read_validator(&env, &validator);
+        info.commission_bps = commission_bps;
+        write_validator(&env, &info);
+    }
+
+    pub fn set_validator_active(env: Env, validator: Address, active: bool) {
+        require_role(&env, Role::ValidatorManager);
+        extend_instance(&env);
+        let mut info = read_validator(&env, &validator);
+        info.active = active;
+        write_validator(&env, &info);
+        require_valid_weights(&env);
+    }
+
+    /// Record the XLM currently delegated to `validator`. Allocations across all
+    /// validators may not exceed the staked total.
+    pub fn set_validator_allocation(env: Env, validator: Address, amount: i128) {
+        require_role(&env, Role::ValidatorManager);
+        if amount < 0 {
+            panic!("allocation must be non-negative");
+        }
+        extend_instance(&env);
+        accrue_rewards(&env);
+
+        let mut info = read_validator(&env, &validator);
+        let total_allocated = read_i128(&env, &DataKey::TotalAllocated) - info.allocated + amount;
+        if total_allocated > read_i128(&env, &DataKey::TotalXlmStaked) {
+            panic!("allocation exceeds total staked");
+        }
+
+        info.allocated = amount;
+        write_validator(&env, &info);
+        write_i128(&env, &DataKey::TotalAllocated, total_allocated);
+    }
+
+    // ==========================================================
+    // View functions
+    // ==========================================================
+
+    /// XLM per sXLM scaled by `RATE_PRECISION`, counting vested rewards only.
+    pub fn get_exchange_rate(env: Env) -> i128 {
+        extend_instance(&env);
+        let total_staked = current_total_staked(&env);
+        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+        exchange_rate(total_staked, total_supply)
+    }
+
+    /// Exchange rate recorded by the last checkpoint at or before `ledger`.
+    /// Panics if `ledger` predates the retained history.
+    pub fn get_rate_at(env: Env, ledger: u32) -> i128 {
+        extend_instance(&env);
+        let history = read_rate_history(&env);
+        if history.is_empty() {
+            return exchange_rate(
+                current_total_staked(&env),
+                read_i128(&env, &DataKey::TotalSxlmSupply),
+            );
+        }
+        rate_at(&history, ledger).expect("ledger before rate history")
+    }
+
+    /// Time-weighted average of the checkpointed rate over the last
+    /// `window_ledgers` ledgers, each rate weighted by how many ledgers it
+    /// stood. The current ledger carries no weight, so the rate cannot be
+    /// moved within the block that reads it. A window longer than the
+    /// retained history is shortened to it.
+    pub fn get_twap_rate(env: Env, window_ledgers: u32) -> i128 {
+        extend_instance(&env);
+        let history = read_rate_history(&env);
+        let last = match history.last() {
+            Some(last) => last,
+            None => {
+                return exchange_rate(
+                    current_total_staked(&env),
+                    read_i128(&env, &DataKey::TotalSxlmSupply),
+                )
+            }
+        };
+
+        let now = env.ledger().sequence();
+        let window_start = now.saturating_sub(window_ledgers);
+        let mut segment_end = now;
+        let mut weighted: i128 = 0;
+        let mut covered: i128 = 0;
+        for checkpoint in history.iter().rev() {
+            let segment_start = checkpoint.ledger.max(window_start);
+            if segment_end > segment_start {
+                let length = (segment_end - segment_start) as i128;
+                weighted += checkpoint.rate * length;
+                covered += length;
+                segment_end = segment_start;
+            }
+            if checkpoint.ledger <= window_start {
+                break;
+            }
+        }
+
+        if covered == 0 {
+            last.rate
+        } else {
+            weighted / covered
+        }
+    }
+
+    /// Retained exchange-rate checkpoints, oldest first.
+    pub fn get_rate_history(env: Env) -> Vec<RateCheckpoint> {
+        extend_instance(&env);
+        read_rate_history(&env)
+    }
+
+    /// sXLM that `deposit` would mint for `xlm_amount` right now.
+    pub fn preview_deposit(env: Env, xlm_amount: i128) -> i128 {
+        extend_instance(&env);
+        let total_staked = current_total_staked(&env);
+        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+        if is_insolvent(&env) {
+            return 0;
+        }
+        (sxlm_for_xlm(xlm_amount, total_staked, total_supply) - locked_sxlm(total_supply)).max(0)
+    }
+
+    /// XLM that `request_withdrawal` would return for `sxlm_amount` right now,
+    /// and whether it would be paid instantly or queued.
+    pub fn preview_withdrawal(env: Env, sxlm_amount: i128) -> WithdrawalPreview {
+        extend_instance(&env);
+        let total_staked = current_total_staked(&env);
+        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+        let xlm_amount = xlm_for_sxlm(sxlm_amount, total_staked, total_supply);
+        let instant = read_i128(&env, &DataKey::LiquidityBuffer) >= xlm_amount
+            && !is_operation_paused(&env, Operation::InstantWithdrawal);
+        let current_ledger = env.ledger().sequence();
+
+        WithdrawalPreview {
+            xlm_amount,
+            instant,
+            unlock_ledger: if instant {
+                current_ledger
+            } else {
+                current_ledger + read_cooldown(&env)
+            },
+        }
+    }
+
+    /// Largest XLM amount a withdrawal can return without being queued.
+    pub fn max_instant_withdrawal(env: Env) -> i128 {
+        extend_instance(&env);
+        if is_operation_paused(&env, Operation::InstantWithdrawal) {
+            return 0;
+        }
+        read_i128(&env, &DataKey::LiquidityBuffer)
+    }
+
+    pub fn total_xlm_staked(env: Env) -> i128 {
+        extend_instance(&env);
+        current_total_staked(&env)
+    }
+
+    /// Rewards added but not yet vested into the staked total.
+    pub fn unvested_rewards(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::UnvestedRewards) - vested_rewards(&env)
+    }
+
+    pub fn reward_vesting_period(env: Env) -> u32 {
+        extend_instance(&env);
+        read_vesting_period(&env)
+    }
+
+    /// Ledger at which currently streaming rewards are fully vested.
+    pub fn reward_vesting_end(env: Env) -> u32 {
+        extend_instance(&env);
+        env.storage()
+            .instance()
+            .get(&DataKey::RewardVestingEnd)
+            .unwrap_or(0)
+    }
+
+    pub fn total_sxlm_supply(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::TotalSxlmSupply)
+    }
+
+    pub fn liquidity_buffer(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::LiquidityBuffer)
+    }
+
+    pub fn target_buffer_bps(env: Env) -> u32 {
+        extend_instance(&env);
+        read_target_buffer_bps(&env)
+    }
+
+    /// Buffer size, in XLM, that deposits currently refill to.
+    pub fn target_buffer(env: Env) -> i128 {
+        extend_instance(&env);
+        target_buffer(&env, current_total_staked(&env))
+    }
+
+    /// XLM `provider` has put into the buffer and not yet removed.
+    pub fn buffer_contribution(env: Env, provider: Address) -> i128 {
+        extend_instance(&env);
+        read_buffer_contribution(&env, &provider)
+    }
+
+    pub fn buffer_stats(env: Env) -> BufferStats {
+        extend_instance(&env);
+        let buffer = read_i128(&env, &DataKey::LiquidityBuffer);
+        let liquidity_provided = read_i128(&env, &DataKey::LiquidityProvided);
+        let pending_withdrawals = read_i128(&env, &DataKey::PendingWithdrawalXlm);
+
+        let utilization_bps = if liquidity_provided > 0 {
+            (liquidity_provided - buffer.min(liquidity_provided)) * BPS_DENOMINATOR
+                / liquidity_provided
+        } else {
+            0
+        };
+        let coverage_bps = if pending_withdrawals > 0 {
+            buffer * BPS_DENOMINATOR / pending_withdrawals
+        } else {
+            BPS_DENOMINATOR
+        };
+
+        BufferStats {
+            buffer,
+            target: target_buffer(&env, current_total_staked(&env)),
+            liquidity_provided,
+            pending_withdrawals,
+            utilization_bps,
+            coverage_bps,
+        }
+    }
+
+    pub fn treasury_balance(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::TreasuryBalance)
+    }
+
+    /// Read-only version of `reconcile`.
+    pub fn health_check(env: Env) -> ReconcileReport {
+        extend_instance(&env);
+        build_reconcile_report(&env)
+    }
+
+    pub fn rate_limits(env: Env) -> RateLimits {
+        extend_instance(&env);
+        read_rate_limits(&env)
+    }
+
+    pub fn get_pending_rate_change(env: Env, id: u64) -> PendingRateChange {
+        extend_instance(&env);
+        read_pending_rate_change(&env, id)
+    }
+
+    /// Current holder of `role`; the admin unless it has been granted.
+    pub fn role_holder(env: Env, role: Role) -> Address {
+        extend_instance(&env);
+        read_role_holder(&env, role)
+    }
+
+    pub fn has_role(env: Env, role: Role, account: Address) -> bool {
+        extend_instance(&env);
+        read_role_holder(&env, role) == account
+    }
+
+    /// Address that receives protocol fees.
+    pub fn treasury(env: Env) -> Address {
+        extend_instance(&env);
+        read_treasury(&env)
+    }
+
+    pub fn auto_sweep_fees(env: Env) -> bool {
+        extend_instance(&env);
+        env.storage()
+            .instance()
+            .get(&DataKey::AutoSweepFees)
+            .unwrap_or(false)
+    }
+
+    pub fn insurance_fund(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::InsuranceFund)
+    }
+
+    pub fn insurance_fee_bps(env: Env) -> u32 {
+        extend_instance(&env);
+        read_insurance_fee_bps(&env)
+    }
+
+    pub fn is_paused(env: Env) -> bool {
+        extend_instance(&env);
+        is_paused(&env)
+    }
+
+    /// Whether `operation` is currently refused, on its own or by `pause`.
+    pub fn is_operation_paused(env: Env, operation: Operation) -> bool {
+        extend_instance(&env);
+        is_operation_paused(&env, operation)
+    }
+
+    /// True once a slash has left outstanding sXLM with no staked XLM behind
+    /// it. Deposits, withdrawals, cancellations and rewards are refused until
+    /// `recapitalize` is called.
+    pub fn is_insolvent(env: Env) -> bool {
+        extend_instance(&env);
+        is_insolvent(&env)
+    }
+
+    pub fn protocol_fee_bps(env: Env) -> i128 {
+        extend_instance(&env);
+        read_protocol_fee_bps(&env)
+    }
+
+    pub fn fee_splits(env: Env) -> Vec<FeeSplit> {
+        extend_instance(&env);
+        read_fee_splits(&env)
+    }
+
+    /// Unclaimed fee split balance of `recipient`.
+    pub fn fee_share_balance(env: Env, recipient: Address) -> i128 {
+        extend_instance(&env);
+        env.storage()
+            .persistent()
+            .get(&DataKey::FeeShare(recipient))
+            .unwrap_or(0)
+    }
+
+    pub fn get_cooldown_period(env: Env) -> u32 {
+        extend_instance(&env);
+        read_cooldown(&env)
+    }
+
+    /// Look up an open withdrawal request. Claimed requests are pruned and no
+    /// longer found.
+    pub fn get_withdrawal(env: Env, withdrawal_id: u64) -> WithdrawalRequest {
+        extend_instance(&env);
+        read_withdrawal(&env, withdrawal_id).expect("withdrawal not found")
+    }
+
+    /// Address currently approved to transfer an open withdrawal, if any.
+    pub fn get_withdrawal_approval(env: Env, withdrawal_id: u64) -> Option<Address> {
+        extend_instance(&env);
+        read_withdrawal_approval(&env, withdrawal_id)
+    }
+
+    /// Number of withdrawal requests ever created (ids run from 0 to count - 1).
+    pub fn withdrawal_count(env: Env) -> u64 {
+        extend_instance(&env);
+        withdrawal_counter(&env)
+    }
+
+    /// Total XLM owed to open withdrawal requests.
+    pub fn pending_withdrawal_xlm(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::PendingWithdrawalXlm)
+    }
+
+    /// Number of open withdrawal requests owned by `user`.
+    pub fn get_user_withdrawal_count(env: Env, user: Address) -> u32 {
+        extend_instance(&env);
+        read_user_withdrawals(&env, &user).len()
+    }
+
+    /// List `user`'s open withdrawal requests, oldest first.
+    /// `start` is an offset into the user's list; `limit` is capped at `MAX_PAGE_SIZE`.
+    pub fn get_user_withdrawals(
+        env: Env,
+        user: Address,
+        start: u32,
+        limit: u32,
+    ) -> Vec<WithdrawalRequest> {
+        extend_instance(&env);
+        let ids = read_user_withdrawals(&env, &user);
+        let end = ids.len().min(start.saturating_add(clamp_page_size(limit)));
+
+        let mut page = Vec::new(&env);
+        for i in start..end {
+            if let Some(request) = read_withdrawal(&env, ids.get_unchecked(i)) {
+                page.push_back(request);
+            }
+        }
+        page
+    }
+
+    /// Scan up to `limit` withdrawal ids starting at `cursor` and return the
+    /// open requests among them, plus the cursor for the next page. The scan
+    /// is finished once the returned cursor reaches `withdrawal_count()`.
+    pub fn get_pending_withdrawals(
+        env: Env,
+        cursor: u64,
+        limit: u32,
+    ) -> (Vec<WithdrawalRequest>, u64) {
+        extend_instance(&env);
+        let count = withdrawal_counter(&env);
+        let start = cursor.max(read_queue_head(&env)).min(count);
+        let end = count.min(start + clamp_page_size(limit) as u64);
+
+        let mut page = Vec::new(&env);
+        for id in start..end {
+            if let Some(request) = read_withdrawal(&env, id) {
+                page.push_back(request);
+            }
+        }
+        (page, end)
+    }
+
+    pub fn get_validators(env: Env) -> Vec<Address> {
+        extend_instance(&env);
+        read_validator_list(&env)
+    }
+
+    pub fn get_validator(env: Env, validator: Address) -> ValidatorInfo {
+        extend_instance(&env);
+        read_validator(&env, &validator)
+    }
+
+    /// Registry entries for every registered validator, in registration order.
+    pub fn get_validator_infos(env: Env) -> Vec<ValidatorInfo> {
+        extend_instance(&env);
+        let mut infos = Vec::new(&env);
+        for validator in read_validator_list(&env).iter() {
+            infos.push_back(read_validator(&env, &validator));
+        }
+        infos
+    }
+
+    /// XLM each active validator should hold given its target weight.
+    pub fn get_target_allocations(env: Env) -> Map<Address, i128> {
+        extend_instance(&env);
+        let total_staked = current_total_staked(&env);
+        let mut targets = Map::new(&env);
+        for validator in read_validator_list(&env).iter() {
+            let info = read_validator(&env, &validator);
+            if info.active {
+                targets.set(
+                    validator,
+                    total_staked * info.target_weight_bps as i128 / BPS_DENOMINATOR,
+                );
+            }
+        }
+        targets
+    }
+
+    pub fn total_allocated(env: Env) -> i128 {
+        extend_instance(&env);
+        read_i128(&env, &DataKey::TotalAllocated)
+    }
+
+    pub fn admin(env: Env) -> Address {
+        extend_instance(&env);
+        read_admin(&env)
+    }
+}
+
+use soroban_sdk::contractclient;
+
+#[contractclient(name = "SxlmTokenClient")]
+pub trait SxlmTokenInterface {
+    fn mint(env: Env, to: Address, amount: i128);
+    fn burn(env: Env, from: Address, amount: i128);
+    fn balance(env: Env, id: Address) -> i128;
+    fn total_supply(env: Env) -> i128;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soroban_sdk::testutils::{Address as _, Ledger};
+    use soroban_sdk::token::StellarAssetClient;
+    use soroban_sdk::Env;
+
+    fn setup_staking(env: &Env) -> (StakingContractClient<'_>, Address, Address, Address) {
+        let contract_id = env.register_contract(None, StakingContract);
+        let client = StakingContractClient::new(env, &contract_id);
+        let admin = Address::generate(env);
+        let sxlm_token = Address::generate(env);
+        let native_token = Address::generate(env);
+
+        client.initialize(&admin, &sxlm_token, &native_token, &17280u32);
+        (client, admin, sxlm_token, native_token)
+    }
+
+    /// Staking contract wired to real sXLM and XLM asset contracts.
+    /// Returns (client, admin, sxlm_token, native_token).
+    fn setup_with_tokens(env: &Env) -> (StakingContractClient<'_>, Address, Address, Address) {
+        env.mock_all_auths();
+        let admin = Address::generate(env);
+        let contract_id = env.register_contract(None, StakingContract);
+        // The staking contract is the sXLM issuer, so its mint calls self-authorize.
+        let sxlm_id = env.register_stellar_asset_contract_v2(contract_id.clone()).address();
+        let native_id = env.register_stellar_asset_contract_v2(admin.clone()).address();
+
+        let client = StakingContractClient::new(env, &contract_id);
+        client.initialize(&admin, &sxlm_id, &native_id, &100u32);
+        (client, admin, sxlm_id, native_id)
+    }
+
+    fn fund(env: &Env, native_id: &Address, to: &Address, amount: i128) {
+        StellarAssetClient::new(env, native_id).mint(to, &amount);
+    }
+
+    #[test]
+    fn test_exchange_rate_initial() {
+        let env = Env::default();
+        let (client, _, _, _) = setup_staking(&env);
+        assert_eq!(client.get_exchange_rate(), RATE_PRECISION);
+        assert_eq!(client.total_xlm_staked(), 0);
+        assert_eq!(client.total_sxlm_supply(), 0);
+    }
+
+    #[test]
+    fn test_view_functions() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, admin, _, _) = setup_staking(&env);
+        assert_eq!(client.liquidity_buffer(), 0);
+        assert_eq!(client.admin(), admin);
+        assert_eq!(client.get_validators().len(), 0);
+        assert_eq!(client.is_paused(), false);
+        assert_eq!(client.treasury_balance(), 0);
+        assert_eq!(client.protocol_fee_bps(), DEFAULT_PROTOCOL_FEE_BPS);
+    }
+
+    #[test]
+    #[should_panic(expected = "already initialized")]
+    fn test_double_initialize_panics() {
+        let env = Env::default();
+        let (client, admin, sxlm, native) = setup_staking(&env);
+        client.initialize(&admin, &sxlm, &native, &100u32);
+    }
+
+    #[test]
+    fn test_add_rewards_with_fee() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _, _) = setup_staking(&env);
+        let gross_reward: i128 = 1000_0000000;
+        client.add_rewards(&gross_reward);
+        assert_eq!(client.total_xlm_staked(), 900_0000000);
+        assert_eq!(client.treasury_balance(), 100_0000000);
+    }
+
+    #[test]
+    fn test_withdraw_fees_partial() {
+        let env = Env::default();
+        env.mock_all_auths();
+
+        let admin = Address::generate(&env);
+        let sxlm_id = env.register_stellar_asset_contract_v2(Address::generate(&env)).address();
+        let native_id = env.register_stellar_asset_contract_v2(admin.clone()).address();
+
+        let contract_id = env.register_contract(None, StakingContract);
+        let client = StakingContractClient::new(&env, &contract_id);
+        client.initialize(&admin, &sxlm_id, &native_id, &17280u32);
+
+        // Fund contract with XLM so withdraw_fees can transfer out
+        let sac_client = soroban_sdk::token::StellarAssetClient::new(&env, &native_id);
+        sac_client.mint(&contract_id, &10_000_0000000);
+
+        // add_rewards builds treasury (10% of 1000 = 100 treasury)
+        client.add_rewards(&1000_0000000);
+        assert_eq!(client.treasury_balance(), 100_0000000);
+
+        let token_client = soroban_sdk::token::Client::new(&env, &native_id);
+        let admin_balance_before = token_client.balance(&admin);
+
+        // Withdraw partial (50 XLM)
+        client.withdraw_fees(&50_0000000);
+        assert_eq!(client.treasury_balance(), 50_0000000);
+        assert_eq!(token_client.balance(&admin) - admin_balance_before, 50_0000000);
+
+        // Withdraw remaining (pass 0 = withdraw all)
+        client.withdraw_fees(&0);
+        assert_eq!(client.treasury_balance(), 0);
+    }
+
+    #[test]
+    fn test_pause_and_unpause() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _, _) = setup_staking(&env);
+        assert_eq!(client.is_paused(), false);
+        client.pause();
+        assert_eq!(client.is_paused(), true);
+        client.unpause();
+        assert_eq!(client.is_paused(), false);
+    }
+
+    #[test]
+    fn test_user_withdrawals_paged() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        fund(&env, &native_id, &alice, 1000_0000000);
+        fund(&env, &native_id, &bob, 1000_0000000);
+        client.deposit(&alice, &500_0000000);
+        client.deposit(&bob, &500_0000000);
+
+        // Empty buffer: every request is queued.
+        client.request_withdrawal(&alice, &10_0000000);
+        client.request_withdrawal(&bob, &20_0000000);
+        client.request_withdrawal(&alice, &30_0000000);
+        client.request_withdrawal(&alice, &40_0000000);
+
+        assert_eq!(client.withdrawal_count(), 4);
+        assert_eq!(client.get_user_withdrawal_count(&alice), 3);
+        assert_eq!(client.get_user_withdrawal_count(&bob), 1);
+
+        let first = client.get_user_withdrawals(&alice, &0, &2);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first.get(0).unwrap().id, 0);
+        assert_eq!(first.get(1).unwrap().id, 2);
+        let second = client.get_user_withdrawals(&alice, &2, &2);
+        assert_eq!(second.len(), 1);
+        assert_eq!(second.get(0).unwrap().xlm_amount, 40_0000000);
+        assert_eq!(client.get_user_withdrawals(&alice, &5, &2).len(), 0);
+    }
+
+    #[test]
+    fn test_claim_prunes_withdrawal() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &500_0000000);
+        for _ in 0..3 {
+            client.request_withdrawal(&user, &10_0000000);
+        }
+        // Queued XLM moves from the staked pool to the pending liability.
+        assert_eq!(client.total_xlm_staked(), 470_0000000);
+        assert_eq!(client.pending_withdrawal_xlm(), 30_0000000);
+        assert_eq!(client.get_exchange_rate(), RATE_PRECISION);
+
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&user, &0);
+        client.claim_withdrawal(&user, &1);
+        assert_eq!(client.pending_withdrawal_xlm(), 10_0000000);
+        assert_eq!(client.total_xlm_staked(), 470_0000000);
+        assert!(client.try_get_withdrawal(&1).is_err());
+
+        let (pending, next) = client.get_pending_withdrawals(&0, &10);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.get(0).unwrap().id, 2);
+        assert_eq!(next, 3);
+
+        let (page, next) = client.get_pending_withdrawals(&next, &10);
+        assert_eq!(page.len(), 0);
+        assert_eq!(next, client.withdrawal_count());
+
+        let open = client.get_user_withdrawals(&user, &0, &0);
+        assert_eq!(open.len(), 1);
+        assert_eq!(open.get(0).unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_migrate_legacy_withdrawal_queue() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &500_0000000);
+
+        // Recreate the old layout: every request in one map, queued XLM still
+        // counted as staked.
+        env.as_contract(&client.address, || {
+            let mut legacy: Map<u64, WithdrawalRequest> = Map::new(&env);
+            for (id, claimed) in [(0u64, true), (1, false), (2, false)] {
+                legacy.set(
+                    id,
+                    WithdrawalRequest {
+                        id,
+                        user: user.clone(),
+                        xlm_amount: 25_0000000,
+                        unlock_ledger: 100,
+                        claimed,
+                    },
+                );
+            }
+            env.storage().persistent().set(&DataKey::WithdrawalQueue, &legacy);
+            env.storage().instance().set(&DataKey::WithdrawalCounter, &3u64);
+        });
+
+        assert_eq!(client.migrate_withdrawal_queue(&2), 1);
+        assert_eq!(client.migrate_withdrawal_queue(&2), 0);
+        assert_eq!(client.migrate_withdrawal_queue(&2), 0);
+
+        assert!(client.try_get_withdrawal(&0).is_err());
+        assert_eq!(client.get_withdrawal(&2).xlm_amount, 25_0000000);
+        assert_eq!(client.pending_withdrawal_xlm(), 50_0000000);
+        assert_eq!(client.total_xlm_staked(), 450_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&user), 2);
+
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&user, &1);
+        assert_eq!(client.pending_withdrawal_xlm(), 25_0000000);
+    }
+
+    #[test]
+    fn test_claim_withdrawals_skips_locked() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let xlm = soroban_sdk::token::Client::new(&env, &native_id);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &500_0000000);
+
+        client.request_withdrawal(&user, &10_0000000);
+        client.request_withdrawal(&user, &20_0000000);
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+        client.request_withdrawal(&user, &30_0000000);
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+
+        let before = xlm.balance(&user);
+        let ids = soroban_sdk::vec![&env, 0u64, 1, 2];
+        assert_eq!(client.claim_withdrawals(&user, &ids), 30_0000000);
+        assert_eq!(xlm.balance(&user) - before, 30_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&user), 1);
+
+        // Nothing matured yet: no transfer, nothing closed.
+        assert_eq!(client.claim_all_matured(&user, &0), 0);
+
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+        assert_eq!(client.claim_all_matured(&user, &0), 30_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&user), 0);
+        assert_eq!(client.pending_withdrawal_xlm(), 0);
+    }
+
+    #[test]
+    fn test_claim_all_matured_reaches_past_the_first_page() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &500_0000000);
+
+        // A full page of slow requests, then one under a shorter cooldown.
+        client.set_cooldown_period(&1000);
+        for _ in 0..MAX_PAGE_SIZE {
+            client.request_withdrawal(&user, &1_0000000);
+        }
+        client.set_cooldown_period(&100);
+        client.request_withdrawal(&user, &1_0000000);
+        let late = client.get_withdrawal(&(MAX_PAGE_SIZE as u64)).xlm_amount;
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+
+        assert_eq!(client.claim_all_matured(&user, &0), 0);
+        assert_eq!(client.claim_all_matured(&user, &MAX_PAGE_SIZE), late);
+        assert_eq!(client.get_user_withdrawal_count(&user), MAX_PAGE_SIZE);
+        assert_eq!(client.claim_all_matured(&user, &(MAX_PAGE_SIZE + 5)), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "not your withdrawal")]
+    fn test_claim_withdrawals_rejects_foreign_id() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        fund(&env, &native_id, &alice, 100_0000000);
+        fund(&env, &native_id, &bob, 100_0000000);
+        client.deposit(&alice, &100_0000000);
+        client.deposit(&bob, &100_0000000);
+        client.request_withdrawal(&alice, &10_0000000);
+        client.request_withdrawal(&bob, &10_0000000);
+
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawals(&alice, &soroban_sdk::vec![&env, 0u64, 1]);
+    }
+
+    #[test]
+    fn test_cancel_withdrawal_remints_at_current_rate() {
+        let env = Env::default();
+        let (client, _, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+        client.request_withdrawal(&user, &50_0000000);
+        assert_eq!(sxlm.balance(&user), 50_0000000 - LOCKED_SXLM);
+
+        // Rate goes to 1.9 while the request waits: 50 XLM buys back ~26.3 sXLM.
+        client.add_rewards(&50_0000000);
+        assert_eq!(client.get_exchange_rate(), 19_000_000);
+
+        let minted = client.cancel_withdrawal(&user, &0);
+        assert_eq!(minted, 50_0000000 * 50_0000000 / 95_0000000);
+        assert_eq!(sxlm.balance(&user), 50_0000000 - LOCKED_SXLM + minted);
+        assert_eq!(client.total_sxlm_supply(), 50_0000000 + minted);
+        assert_eq!(client.total_xlm_staked(), 145_0000000);
+        assert_eq!(client.pending_withdrawal_xlm(), 0);
+        assert_eq!(client.get_user_withdrawal_count(&user), 0);
+        assert!(client.try_get_withdrawal(&0).is_err());
+    }
+
+    #[test]
+    fn test_cancel_withdrawal_does_not_dodge_a_slash() {
+        let env = Env::default();
+        let (client, _, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        fund(&env, &native_id, &alice, 1000_0000000);
+        fund(&env, &native_id, &bob, 1000_0000000);
+        client.deposit(&alice, &100_0000000);
+        client.deposit(&bob, &100_0000000);
+        client.request_withdrawal(&bob, &50_0000000);
+
+        // Rate drops to 0.8 while the request waits: at the current rate the
+        // 50 XLM would buy back 62.5 sXLM, more than bob burned.
+        client.apply_slashing(&30_0000000);
+        assert_eq!(client.get_exchange_rate(), 8_000_000);
+
+        assert_eq!(client.cancel_withdrawal(&bob, &0), 50_0000000);
+        assert_eq!(sxlm.balance(&bob), 100_0000000);
+        assert_eq!(client.total_xlm_staked(), 170_0000000);
+        assert_eq!(client.get_exchange_rate(), 8_500_000);
+    }
+
+    #[test]
+    fn test_transfer_withdrawal() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let xlm = soroban_sdk::token::Client::new(&env, &native_id);
+        let seller = Address::generate(&env);
+        let desk = Address::generate(&env);
+        fund(&env, &native_id, &seller, 100_0000000);
+        client.deposit(&seller, &100_0000000);
+        client.request_withdrawal(&seller, &40_0000000);
+
+        client.transfer_withdrawal(&seller, &desk, &0);
+        assert_eq!(client.get_withdrawal(&0).user, desk);
+        assert_eq!(client.get_user_withdrawal_count(&seller), 0);
+        assert_eq!(client.get_user_withdrawal_count(&desk), 1);
+        assert!(client.try_claim_withdrawal(&seller, &0).is_err());
+
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&desk, &0);
+        assert_eq!(xlm.balance(&desk), 40_0000000);
+    }
+
+    #[test]
+    fn test_transfer_withdrawal_respects_recipient_cap() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let spammer = Address::generate(&env);
+        let victim = Address::generate(&env);
+        fund(&env, &native_id, &spammer, 100_0000000);
+        fund(&env, &native_id, &victim, 100_0000000);
+        client.deposit(&spammer, &100_0000000);
+        client.deposit(&victim, &100_0000000);
+
+        for id in 0..MAX_INBOUND_WITHDRAWALS as u64 + 1 {
+            client.request_withdrawal(&spammer, &1);
+            if id < MAX_INBOUND_WITHDRAWALS as u64 {
+                client.transfer_withdrawal(&spammer, &victim, &id);
+            } else {
+                assert!(client.try_transfer_withdrawal(&spammer, &victim, &id).is_err());
+            }
+        }
+        assert_eq!(client.get_user_withdrawal_count(&victim), MAX_INBOUND_WITHDRAWALS);
+
+        // The victim's own exits still go through.
+        client.request_withdrawal(&victim, &10_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&victim), MAX_INBOUND_WITHDRAWALS + 1);
+    }
+
+    #[test]
+    fn test_transfer_withdrawal_from_consumes_approval() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let owner = Address::generate(&env);
+        let spender = Address::generate(&env);
+        let buyer = Address::generate(&env);
+        fund(&env, &native_id, &owner, 100_0000000);
+        client.deposit(&owner, &100_0000000);
+        client.request_withdrawal(&owner, &40_0000000);
+
+        assert!(client
+            .try_transfer_withdrawal_from(&spender, &owner, &buyer, &0)
+            .is_err());
+
+        client.approve_withdrawal(&owner, &spender, &0);
+        assert_eq!(client.get_withdrawal_approval(&0), Some(spender.clone()));
+        client.transfer_withdrawal_from(&spender, &owner, &buyer, &0);
+
+        assert_eq!(client.get_withdrawal(&0).user, buyer);
+        assert_eq!(client.get_withdrawal_approval(&0), None);
+        assert!(client
+            .try_transfer_withdrawal_from(&spender, &buyer, &owner, &0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_previews_match_execution() {
+        let env = Env::default();
+        let (client, _, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let user = Address::generate(&env);
+        let admin = client.admin();
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+
+        client.deposit(&user, &300_0000000);
+        client.add_rewards(&30_0000000);
+
+        let quoted = client.preview_deposit(&100_0000000);
+        let before = sxlm.balance(&user);
+        client.deposit(&user, &100_0000000);
+        assert_eq!(sxlm.balance(&user) - before, quoted);
+
+        client.add_liquidity(&50_0000000);
+        assert_eq!(client.max_instant_withdrawal(), 50_0000000);
+
+        let small = client.preview_withdrawal(&20_0000000);
+        assert!(small.instant);
+        assert_eq!(small.unlock_ledger, env.ledger().sequence());
+        client.request_withdrawal(&user, &20_0000000);
+        assert_eq!(client.liquidity_buffer(), 50_0000000 - small.xlm_amount);
+
+        let large = client.preview_withdrawal(&100_0000000);
+        assert!(!large.instant);
+        assert_eq!(large.unlock_ledger, env.ledger().sequence() + 100);
+        client.request_withdrawal(&user, &100_0000000);
+        let request = client.get_withdrawal(&0);
+        assert_eq!(request.xlm_amount, large.xlm_amount);
+        assert_eq!(request.unlock_ledger, large.unlock_ledger);
+    }
+
+    #[test]
+    fn test_deposit_with_limits() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+
+        let quoted = client.preview_deposit(&100_0000000);
+        // A reward lands first and moves the rate past the user's tolerance.
+        client.add_rewards(&50_0000000);
+        assert!(client
+            .try_deposit_with_limits(&user, &100_0000000, &quoted, &env.ledger().sequence())
+            .is_err());
+
+        let requoted = client.preview_deposit(&100_0000000);
+        let minted =
+            client.deposit_with_limits(&user, &100_0000000, &requoted, &env.ledger().sequence());
+        assert_eq!(minted, requoted);
+    }
+
+    #[test]
+    fn test_request_withdrawal_with_limits() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+
+        let quoted = client.preview_withdrawal(&50_0000000).xlm_amount;
+        client.apply_slashing(&10_0000000);
+        assert!(client
+            .try_request_withdrawal_with_limits(&user, &50_0000000, &quoted, &env.ledger().sequence())
+            .is_err());
+
+        let deadline = env.ledger().sequence();
+        env.ledger().with_mut(|l| l.sequence_number += 1);
+        assert!(client
+            .try_request_withdrawal_with_limits(&user, &50_0000000, &0, &deadline)
+            .is_err());
+
+        let paid = client.request_withdrawal_with_limits(
+            &user,
+            &50_0000000,
+            &45_0000000,
+            &env.ledger().sequence(),
+        );
+        assert_eq!(paid, 45_0000000);
+        assert_eq!(client.get_withdrawal(&0).xlm_amount, 45_0000000);
+    }
+
+    #[test]
+    fn test_deposit_for_beneficiary() {
+        let env = Env::default();
+        let (client, _, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let xlm = soroban_sdk::token::Client::new(&env, &native_id);
+        let payer = Address::generate(&env);
+        let beneficiary = Address::generate(&env);
+        fund(&env, &native_id, &payer, 100_0000000);
+
+        let minted = client.deposit_for(&payer, &beneficiary, &60_0000000);
+        assert_eq!(minted, 60_0000000 - LOCKED_SXLM);
+        assert_eq!(xlm.balance(&payer), 40_0000000);
+        assert_eq!(sxlm.balance(&payer), 0);
+        assert_eq!(sxlm.balance(&beneficiary), minted);
+    }
+
+    #[test]
+    fn test_request_withdrawal_to_recipient() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let xlm = soroban_sdk::token::Client::new(&env, &native_id);
+        let owner = Address::generate(&env);
+        let recipient = Address::generate(&env);
+        fund(&env, &native_id, &owner, 100_0000000);
+        fund(&env, &native_id, &client.admin(), 100_0000000);
+        client.deposit(&owner, &100_0000000);
+        client.add_liquidity(&20_0000000);
+
+        // Covered by the buffer: paid straight to the recipient.
+        assert_eq!(client.request_withdrawal_to(&owner, &recipient, &20_0000000), 20_0000000);
+        assert_eq!(xlm.balance(&recipient), 20_0000000);
+
+        // Queued: the recipient owns the request and claims it.
+        client.request_withdrawal_to(&owner, &recipient, &30_0000000);
+        assert_eq!(client.get_withdrawal(&0).user, recipient);
+        assert_eq!(client.get_user_withdrawal_count(&owner), 0);
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&recipient, &0);
+        assert_eq!(xlm.balance(&recipient), 50_0000000);
+    }
+
+    #[test]
+    fn test_request_withdrawal_to_respects_recipient_cap() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let spammer = Address::generate(&env);
+        let victim = Address::generate(&env);
+        fund(&env, &native_id, &spammer, 100_0000000);
+        fund(&env, &native_id, &victim, 100_0000000);
+        client.deposit(&spammer, &100_0000000);
+        client.deposit(&victim, &100_0000000);
+
+        for _ in 0..MAX_INBOUND_WITHDRAWALS {
+            client.request_withdrawal_to(&spammer, &victim, &1);
+        }
+        assert!(client.try_request_withdrawal_to(&spammer, &victim, &1).is_err());
+        assert_eq!(client.get_user_withdrawal_count(&victim), MAX_INBOUND_WITHDRAWALS);
+
+        // Requests to oneself are never refused.
+        client.request_withdrawal_to(&victim, &victim, &10_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&victim), MAX_INBOUND_WITHDRAWALS + 1);
+    }
+
+    #[test]
+    fn test_streamed_rewards_vest_linearly() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+        client.set_reward_vesting_period(&1000);
+
+        client.add_rewards(&100_0000000);
+        assert_eq!(client.total_xlm_staked(), 100_0000000);
+        assert_eq!(client.unvested_rewards(), 90_0000000);
+        assert_eq!(client.get_exchange_rate(), RATE_PRECISION);
+
+        env.ledger().with_mut(|l| l.sequence_number += 250);
+        assert_eq!(client.total_xlm_staked(), 122_5000000);
+        assert_eq!(client.get_exchange_rate(), 12_250_000);
+
+        // A deposit mid-stream is priced on the vested part only.
+        let quoted = client.preview_deposit(&122_5000000);
+        assert_eq!(quoted, 100_0000000);
+        client.deposit(&user, &122_5000000);
+        assert_eq!(client.total_sxlm_supply(), 200_0000000);
+        assert_eq!(client.unvested_rewards(), 67_5000000);
+
+        env.ledger().with_mut(|l| l.sequence_number += 750);
+        assert_eq!(client.unvested_rewards(), 0);
+        assert_eq!(client.total_xlm_staked(), 312_5000000);
+    }
+
+    #[test]
+    fn test_rewards_sandwich_unprofitable_when_streaming() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let holder = Address::generate(&env);
+        let sniper = Address::generate(&env);
+        fund(&env, &native_id, &holder, 1000_0000000);
+        fund(&env, &native_id, &sniper, 1000_0000000);
+        client.deposit(&holder, &1000_0000000);
+        client.set_reward_vesting_period(&1000);
+
+        client.deposit(&sniper, &1000_0000000);
+        client.add_rewards(&100_0000000);
+        let out = client.preview_withdrawal(&1000_0000000).xlm_amount;
+        assert_eq!(out, 1000_0000000);
+    }
+
+    #[test]
+    fn test_validator_registry() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &1000_0000000);
+
+        let v1 = Address::generate(&env);
+        let v2 = Address::generate(&env);
+        client.add_validator(&v1, &500, &6000);
+        client.add_validator(&v2, &300, &4000);
+        assert!(client.try_add_validator(&Address::generate(&env), &0, &1).is_err());
+        assert!(client.try_set_validator_weight(&v2, &4001).is_err());
+
+        let targets = client.get_target_allocations();
+        assert_eq!(targets.get(v1.clone()), Some(600_0000000));
+        assert_eq!(targets.get(v2.clone()), Some(400_0000000));
+
+        client.set_validator_allocation(&v1, &700_0000000);
+        client.set_validator_allocation(&v2, &300_0000000);
+        assert_eq!(client.total_allocated(), 1000_0000000);
+        assert!(client.try_set_validator_allocation(&v2, &300_0000001).is_err());
+
+        client.set_validator_active(&v2, &false);
+        assert_eq!(client.get_target_allocations().len(), 1);
+        assert!(client.try_remove_validator(&v2).is_err());
+        client.set_validator_allocation(&v2, &0);
+        client.remove_validator(&v2);
+
+        let infos = client.get_validator_infos();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos.get(0).unwrap().commission_bps, 500);
+        assert_eq!(client.get_validators(), soroban_sdk::vec![&env, v1]);
+    }
+
+    #[test]
+    fn test_update_validators_assigns_equal_weights() {
+        let env = Env::default();
+        let (client, _, _, _) = setup_with_tokens(&env);
+        let v1 = Address::generate(&env);
+        let v2 = Address::generate(&env);
+        let v3 = Address::generate(&env);
+
+        client.update_validators(&soroban_sdk::vec![&env, v1.clone(), v2.clone(), v3.clone()]);
+        assert_eq!(client.get_validator(&v1).target_weight_bps, 3334);
+        assert_eq!(client.get_validator(&v3).target_weight_bps, 3333);
+
+        client.update_validators(&soroban_sdk::vec![&env, v2.clone(), v3.clone()]);
+        assert_eq!(client.get_validators(), soroban_sdk::vec![&env, v2.clone(), v3]);
+        assert_eq!(client.get_validator(&v2).target_weight_bps, 5000);
+        assert!(client.try_get_validator(&v1).is_err());
+    }
+
+    #[test]
+    fn test_slash_validator_draws_on_insurance_first() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &1000_0000000);
+        let validator = Address::generate(&env);
+        client.add_validator(&validator, &0, &10_000);
+        client.set_validator_allocation(&validator, &1000_0000000);
+
+        // Half of each fee goes to the insurance fund.
+        client.set_insurance_fee_bps(&5000);
+        client.add_rewards(&100_0000000);
+        assert_eq!(client.insurance_fund(), 5_0000000);
+        assert_eq!(client.treasury_balance(), 5_0000000);
+        assert_eq!(client.total_xlm_staked(), 1090_0000000);
+
+        // Fully covered: holders keep their rate.
+        client.slash_validator(&validator, &3_0000000);
+        assert_eq!(client.insurance_fund(), 2_0000000);
+        assert_eq!(client.total_xlm_staked(), 1090_0000000);
+        assert_eq!(client.get_validator(&validator).allocated, 997_0000000);
+        assert_eq!(client.total_allocated(), 997_0000000);
+
+        // Fund runs dry: the uncovered part is socialized.
+        client.slash_validator(&validator, &7_0000000);
+        assert_eq!(client.insurance_fund(), 0);
+        assert_eq!(client.total_xlm_staked(), 1085_0000000);
+
+        assert!(client.try_slash_validator(&validator, &991_0000000).is_err());
+    }
+
+    #[test]
+    fn test_apply_slashing_uses_insurance_fund() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 100_0000000);
+        fund(&env, &native_id, &admin, 100_0000000);
+        client.deposit(&user, &100_0000000);
+
+        client.fund_insurance(&admin, &10_0000000);
+        client.apply_slashing(&15_0000000);
+        assert_eq!(client.insurance_fund(), 0);
+        assert_eq!(client.total_xlm_staked(), 95_0000000);
+    }
+
+    #[test]
+    fn test_set_protocol_fee_bounds() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, _, _, _) = setup_staking(&env);
+        client.set_protocol_fee_bps(&500);
+        assert_eq!(client.protocol_fee_bps(), 500);
+        client.add_rewards(&100_0000000);
+        assert_eq!(client.treasury_balance(), 5_0000000);
+        assert_eq!(client.total_xlm_staked(), 95_0000000);
+
+        client.set_protocol_fee_bps(&(MAX_PROTOCOL_FEE_BPS as u32));
+        assert!(client
+            .try_set_protocol_fee_bps(&(MAX_PROTOCOL_FEE_BPS as u32 + 1))
+            .is_err());
+    }
+
+    #[test]
+    fn test_fee_splits() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let xlm = soroban_sdk::token::Client::new(&env, &native_id);
+        fund(&env, &native_id, &client.address, 1000_0000000);
+        let dao = Address::generate(&env);
+        let partner = Address::generate(&env);
+        client.set_fee_splits(&soroban_sdk::vec![
+            &env,
+            FeeSplit { recipient: dao.clone(), bps: 5000 },
+            FeeSplit { recipient: partner.clone(), bps: 2000 },
+        ]);
+        assert!(client
+            .try_set_fee_splits(&soroban_sdk::vec![
+                &env,
+                FeeSplit { recipient: dao.clone(), bps: 9000 },
+                FeeSplit { recipient: partner.clone(), bps: 1001 },
+            ])
+            .is_err());
+
+        client.add_rewards(&1000_0000000);
+        assert_eq!(client.fee_share_balance(&dao), 50_0000000);
+        assert_eq!(client.fee_share_balance(&partner), 20_0000000);
+        assert_eq!(client.treasury_balance(), 30_0000000);
+
+        assert_eq!(client.claim_fee_share(&dao), 50_0000000);
+        assert_eq!(xlm.balance(&dao), 50_0000000);
+        assert_eq!(client.fee_share_balance(&dao), 0);
+        assert!(client.try_claim_fee_share(&dao).is_err());
+    }
+
+    #[test]
+    fn test_fees_flow_to_treasury() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let xlm = soroban_sdk::token::Client::new(&env, &native_id);
+        fund(&env, &native_id, &client.address, 1000_0000000);
+        assert_eq!(client.treasury(), admin);
+
+        let treasury = Address::generate(&env);
+        client.set_treasury(&treasury);
+        assert_eq!(client.treasury(), treasury);
+
+        client.add_rewards(&100_0000000);
+        client.withdraw_fees(&0);
+        assert_eq!(xlm.balance(&treasury), 10_0000000);
+        assert_eq!(xlm.balance(&admin), 0);
+
+        client.set_auto_sweep_fees(&true);
+        fund(&env, &native_id, &admin, 100_0000000);
+        client.add_rewards_funded(&100_0000000);
+        assert_eq!(xlm.balance(&treasury), 20_0000000);
+        assert_eq!(client.treasury_balance(), 0);
+
+        // Unfunded rewards never pay out XLM the contract has not received.
+        client.add_rewards(&100_0000000);
+        assert_eq!(xlm.balance(&treasury), 20_0000000);
+        assert_eq!(client.treasury_balance(), 10_0000000);
+    }
+
+    #[test]
+    fn test_health_check_balances() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+
+        client.deposit(&user, &500_0000000);
+        client.add_liquidity(&100_0000000);
+        client.request_withdrawal(&user, &50_0000000); // instant
+        client.request_withdrawal(&user, &200_0000000); // queued
+        client.add_rewards_funded(&100_0000000);
+        assert_eq!(client.health_check().surplus, 0);
+
+        let report = client.reconcile();
+        assert_eq!(report.token_balance, 650_0000000);
+        assert_eq!(report.staked, 340_0000000);
+        assert_eq!(report.pending_withdrawals, 200_0000000);
+        assert_eq!(report.protocol_balances, 10_0000000);
+        assert_eq!(report.liquidity_provided, 100_0000000);
+
+        // Unfunded rewards run the books ahead of the balance.
+        client.add_rewards(&10_0000000);
+        assert_eq!(client.health_check().surplus, -10_0000000);
+
+        client.set_require_funded_rewards(&true);
+        assert!(client.try_add_rewards(&10_0000000).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_counts_pending_rewards() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+        client.set_reward_vesting_period(&1000);
+        client.set_rate_limits(&RateLimits {
+            per_call_bps: 1000,
+            window_bps: 0,
+            window_ledgers: 0,
+            delay_ledgers: 50,
+        });
+
+        // Still vesting.
+        client.add_rewards_funded(&10_0000000);
+        assert!(client.unvested_rewards() > 0);
+        assert_eq!(client.reconcile().surplus, 0);
+
+        // Held back by the rate limits.
+        client.add_rewards_funded(&50_0000000);
+        let report = client.reconcile();
+        assert_eq!(report.queued_rewards, 50_0000000);
+        assert_eq!(report.surplus, 0);
+
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+        client.execute_rate_change(&0);
+        assert_eq!(client.health_check().queued_rewards, 0);
+        assert_eq!(client.reconcile().surplus, 0);
+
+        client.add_rewards_funded(&50_0000000);
+        client.veto_rate_change(&1);
+        assert_eq!(client.reconcile().surplus, 0);
+    }
+
+    #[test]
+    fn test_deposits_refill_buffer_to_target() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+
+        assert!(client.try_set_target_buffer_bps(&10_001).is_err());
+        client.set_target_buffer_bps(&1000);
+
+        client.deposit(&user, &100_0000000);
+        assert_eq!(client.liquidity_buffer(), 10_0000000);
+        assert_eq!(client.target_buffer(), 10_0000000);
+
+        // Drain the buffer, then a small deposit goes entirely to refilling it.
+        client.request_withdrawal(&user, &10_0000000);
+        assert_eq!(client.liquidity_buffer(), 0);
+        client.deposit(&user, &5_0000000);
+        assert_eq!(client.liquidity_buffer(), 5_0000000);
+
+        client.deposit(&user, &105_0000000);
+        assert_eq!(client.liquidity_buffer(), 20_0000000);
+    }
+
+    #[test]
+    fn test_process_withdrawal_queue_fifo() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let xlm = token::Client::new(&env, &native_id);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        fund(&env, &native_id, &alice, 1000_0000000);
+        fund(&env, &native_id, &bob, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+
+        client.deposit(&alice, &100_0000000);
+        client.deposit(&bob, &100_0000000);
+        client.request_withdrawal(&alice, &30_0000000);
+        client.request_withdrawal(&bob, &10_0000000);
+        assert_eq!(client.pending_withdrawal_xlm(), 40_0000000);
+
+        // Nothing above target yet.
+        assert_eq!(client.process_withdrawal_queue(&10), 0);
+
+        // 20 XLM over target: alice's 30 does not fit, so bob waits behind her.
+        client.set_target_buffer_bps(&1000);
+        client.add_liquidity(&36_0000000);
+        assert_eq!(client.target_buffer(), 16_0000000);
+        assert_eq!(client.process_withdrawal_queue(&10), 0);
+
+        client.add_liquidity(&20_0000000);
+        assert_eq!(client.process_withdrawal_queue(&10), 2);
+        assert_eq!(xlm.balance(&alice), 930_0000000);
+        assert_eq!(xlm.balance(&bob), 910_0000000);
+        assert_eq!(client.pending_withdrawal_xlm(), 0);
+        assert_eq!(client.liquidity_buffer(), 16_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&alice), 0);
+        assert_eq!(client.health_check().surplus, 0);
+    }
+
+    #[test]
+    fn test_process_withdrawal_queue_skips_pruned_ids() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let xlm = token::Client::new(&env, &native_id);
+        let user = Address::generate(&env);
+        let late = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &late, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.deposit(&user, &500_0000000);
+        client.deposit(&late, &100_0000000);
+
+        // 60 requests claimed out of order, the head one last.
+        for _ in 0..60 {
+            client.request_withdrawal(&user, &1_0000000);
+        }
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        for first in [1u64, 21, 41] {
+            let mut ids = soroban_sdk::Vec::new(&env);
+            for id in first..(first + 20).min(60) {
+                ids.push_back(id);
+            }
+            client.claim_withdrawals(&user, &ids);
+        }
+        client.claim_withdrawal(&user, &0);
+
+        client.request_withdrawal(&late, &10_0000000);
+        let owed = client.get_withdrawal(&60).xlm_amount;
+        client.add_liquidity(&50_0000000);
+
+        // Each call moves the head past the pruned ids it scanned.
+        assert_eq!(client.process_withdrawal_queue(&20), 0);
+        assert_eq!(client.process_withdrawal_queue(&20), 0);
+        assert_eq!(client.process_withdrawal_queue(&20), 1);
+        assert_eq!(xlm.balance(&late), 900_0000000 + owed);
+        assert_eq!(client.pending_withdrawal_xlm(), 0);
+    }
+
+    #[test]
+    fn test_remove_liquidity() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let xlm = token::Client::new(&env, &native_id);
+        let user = Address::generate(&env);
+        let stranger = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+
+        client.deposit(&user, &100_0000000);
+        client.add_liquidity(&50_0000000);
+        assert_eq!(client.buffer_contribution(&admin), 50_0000000);
+
+        client.request_withdrawal(&user, &20_0000000); // instant
+        client.request_withdrawal(&user, &79_0000000); // queued
+        let stats = client.buffer_stats();
+        assert_eq!(stats.buffer, 30_0000000);
+        assert_eq!(stats.utilization_bps, 4000);
+        assert_eq!(stats.coverage_bps, 3797);
+
+        assert!(client.try_remove_liquidity(&stranger, &1_0000000).is_err());
+        assert!(client.try_remove_liquidity(&admin, &40_0000000).is_err());
 
-        write_i128(&env, &DataKey::TreasuryBalance, treasury_bal - withdraw_amount);
+        // The buffer is already short of the queued 79 XLM.
+        assert!(client.try_remove_liquidity(&admin, &1_0000000).is_err());
 
-        env.events().publish(
-            (soroban_sdk::symbol_short!("fee_out"),),
-            (admin, withdraw_amount),
-        );
-    }
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&user, &0);
+        assert_eq!(xlm.balance(&user), 999_0000000);
 
-    pub fn set_treasury(env: Env, treasury: Address) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
-        env.storage().instance().set(&DataKey::Treasury, &treasury);
+        // The target buffer has to stay behind as well.
+        client.set_target_buffer_bps(&10_000);
+        assert!(client.try_remove_liquidity(&admin, &30_0000000).is_err());
+        client.set_target_buffer_bps(&0);
+
+        client.remove_liquidity(&admin, &30_0000000);
+        assert_eq!(xlm.balance(&admin), 980_0000000);
+        assert_eq!(client.buffer_contribution(&admin), 20_0000000);
+        assert_eq!(client.liquidity_buffer(), 0);
+        assert_eq!(client.health_check().surplus, 0);
     }
 
-    // ==========================================================
-    // Slashing
-    // ==========================================================
+    #[test]
+    fn test_rate_history_and_twap() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.set_protocol_fee_bps(&0);
+        env.ledger().with_mut(|l| l.sequence_number = 1000);
 
-    pub fn apply_slashing(env: Env, slash_amount: i128) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        if slash_amount <= 0 {
-            panic!("slash amount must be positive");
-        }
-        extend_instance(&env);
+        client.deposit(&user, &100_0000000);
+        client.deposit(&user, &100_0000000); // same ledger and rate: no new checkpoint
+        assert_eq!(client.get_rate_history().len(), 1);
 
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        if slash_amount > total_staked {
-            panic!("slash amount exceeds total staked");
-        }
+        env.ledger().with_mut(|l| l.sequence_number = 1100);
+        client.add_rewards_funded(&20_0000000); // rate 1.1
 
-        let new_total = total_staked - slash_amount;
-        write_i128(&env, &DataKey::TotalXlmStaked, new_total);
+        env.ledger().with_mut(|l| l.sequence_number = 1200);
+        client.add_rewards_funded(&20_0000000); // rate 1.2
+        client.add_rewards_funded(&20_0000000); // same ledger, rate 1.3 overwrites
+        assert_eq!(client.get_rate_history().len(), 3);
 
-        env.events().publish(
-            (soroban_sdk::symbol_short!("slashed"),),
-            (slash_amount, new_total),
-        );
+        assert_eq!(client.get_rate_at(&1050), 1_0000000);
+        assert_eq!(client.get_rate_at(&1100), 1_1000000);
+        assert_eq!(client.get_rate_at(&5000), 1_3000000);
+        assert!(client.try_get_rate_at(&999).is_err());
 
-        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
-        let new_rate = if total_supply == 0 {
-            RATE_PRECISION
-        } else {
-            new_total * RATE_PRECISION / total_supply
-        };
+        // The jump recorded in the current ledger has no weight yet.
+        assert_eq!(client.get_twap_rate(&200), 1_0500000);
 
-        env.events().publish(
-            (soroban_sdk::symbol_short!("recalib"),),
-            (new_rate, new_total, total_supply),
-        );
+        env.ledger().with_mut(|l| l.sequence_number = 1300);
+        // 100 ledgers at 1.0, 100 at 1.1, 100 at 1.3.
+        assert_eq!(client.get_twap_rate(&300), 1_1333333);
+        assert_eq!(client.get_twap_rate(&100), 1_3000000);
+        // Longer than the history: averaged over what is recorded.
+        assert_eq!(client.get_twap_rate(&10_000), 1_1333333);
     }
 
-    pub fn recalibrate_rate(env: Env) -> i128 {
-        extend_instance(&env);
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
+    #[test]
+    fn test_rate_history_is_capped() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.deposit(&user, &100_0000000);
 
-        let new_rate = if total_supply == 0 {
-            RATE_PRECISION
-        } else {
-            total_staked * RATE_PRECISION / total_supply
-        };
+        for _ in 0..MAX_RATE_CHECKPOINTS + 5 {
+            env.ledger().with_mut(|l| l.sequence_number += 1);
+            client.add_rewards_funded(&1_0000000);
+        }
+        let history = client.get_rate_history();
+        assert_eq!(history.len(), MAX_RATE_CHECKPOINTS);
+        assert_eq!(history.last().unwrap().rate, client.get_exchange_rate());
+    }
 
-        env.events().publish(
-            (soroban_sdk::symbol_short!("recalib"),),
-            (new_rate, total_staked, total_supply),
-        );
+    #[test]
+    fn test_first_deposit_locks_sxlm() {
+        let env = Env::default();
+        let (client, _, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
 
-        new_rate
+        assert!(client.try_deposit(&user, &LOCKED_SXLM).is_err());
+        assert_eq!(client.preview_deposit(&(LOCKED_SXLM / 2)), 0);
+        assert_eq!(client.preview_deposit(&10_0000000), 10_0000000 - LOCKED_SXLM);
+        client.deposit(&user, &10_0000000);
+        assert_eq!(sxlm.balance(&user), 10_0000000 - LOCKED_SXLM);
+        assert_eq!(sxlm.balance(&client.address), LOCKED_SXLM);
+        assert_eq!(client.total_sxlm_supply(), 10_0000000);
+
+        // Even after every holder exits, the locked sXLM keeps the supply up.
+        client.request_withdrawal(&user, &(10_0000000 - LOCKED_SXLM));
+        assert_eq!(client.total_sxlm_supply(), LOCKED_SXLM);
+        assert_eq!(client.get_exchange_rate(), RATE_PRECISION);
+        assert_eq!(client.preview_deposit(&10_0000000), 10_0000000);
     }
 
-    // ==========================================================
-    // Emergency pause
-    // ==========================================================
+    #[test]
+    fn test_first_depositor_inflation_attack_fails() {
+        let env = Env::default();
+        let (client, admin, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let attacker = Address::generate(&env);
+        let victim = Address::generate(&env);
+        fund(&env, &native_id, &attacker, 1000_0000000);
+        fund(&env, &native_id, &victim, 1000_0000000);
+        fund(&env, &native_id, &admin, 500_0000000);
 
-    pub fn pause(env: Env) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
-        env.storage().instance().set(&DataKey::Paused, &true);
-        env.events().publish((soroban_sdk::symbol_short!("paused"),), true);
-    }
+        // Smallest possible stake, then a large reward lands on that supply.
+        client.deposit(&attacker, &(LOCKED_SXLM + 1));
+        assert_eq!(sxlm.balance(&attacker), 1);
+        client.add_rewards_funded(&500_0000000);
+        let rate = client.get_exchange_rate();
+        assert!(rate > 1000 * RATE_PRECISION);
 
-    pub fn unpause(env: Env) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
-        env.storage().instance().set(&DataKey::Paused, &false);
-        env.events().publish((soroban_sdk::symbol_short!("paused"),), false);
+        // The victim still gets shares and loses at most one sXLM stroop's
+        // worth to rounding.
+        let deposit = 100_0000000;
+        client.deposit(&victim, &deposit);
+        let victim_sxlm = sxlm.balance(&victim);
+        assert!(victim_sxlm > 0);
+        let victim_value = client.preview_withdrawal(&victim_sxlm).xlm_amount;
+        assert!(deposit - victim_value <= rate / RATE_PRECISION + 1);
+
+        // Without the locked supply the attacker would own the whole pool,
+        // reward and rounding dust included. Here they own a thousandth of it.
+        let attacker_value = client.preview_withdrawal(&sxlm.balance(&attacker)).xlm_amount;
+        assert!(attacker_value * 1000 <= client.total_xlm_staked());
     }
 
-    // ==========================================================
-    // Liquidity & Validators
-    // ==========================================================
+    #[test]
+    fn test_full_slash_enters_insolvency() {
+        let env = Env::default();
+        let (client, admin, sxlm_id, native_id) = setup_with_tokens(&env);
+        let sxlm = soroban_sdk::token::Client::new(&env, &sxlm_id);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        fund(&env, &native_id, &alice, 1000_0000000);
+        fund(&env, &native_id, &bob, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
 
-    pub fn add_liquidity(env: Env, amount: i128) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        if amount <= 0 {
-            panic!("liquidity amount must be positive");
-        }
-        extend_instance(&env);
+        client.deposit(&alice, &100_0000000);
+        client.request_withdrawal(&alice, &20_0000000); // queued before the slash
+        client.apply_slashing(&80_0000000);
+        assert!(client.is_insolvent());
+        assert_eq!(client.total_xlm_staked(), 0);
 
-        let native_token_addr = read_native_token(&env);
-        let xlm_client = token::Client::new(&env, &native_token_addr);
-        xlm_client.transfer(&admin, &env.current_contract_address(), &amount);
+        // No 1:1 mint against the wiped-out pool, and nothing to redeem yet.
+        assert_eq!(client.preview_deposit(&100_0000000), 0);
+        assert!(client.try_deposit(&bob, &100_0000000).is_err());
+        assert!(client.try_request_withdrawal(&alice, &10_0000000).is_err());
+        assert!(client.try_add_rewards(&10_0000000).is_err());
 
-        let buffer = read_i128(&env, &DataKey::LiquidityBuffer);
-        write_i128(&env, &DataKey::LiquidityBuffer, buffer + amount);
-    }
+        // The queued request was set aside before the slash and still pays.
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&alice, &0);
 
-    pub fn update_validators(env: Env, validators: Vec<Address>) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
-        env.storage().instance().set(&DataKey::Validators, &validators);
+        client.recapitalize(&admin, &40_0000000);
+        assert!(!client.is_insolvent());
+        let alice_sxlm = sxlm.balance(&alice);
+        assert_eq!(
+            client.preview_withdrawal(&alice_sxlm).xlm_amount,
+            alice_sxlm * 40_0000000 / 80_0000000
+        );
+        client.deposit(&bob, &100_0000000);
+        assert_eq!(sxlm.balance(&bob), 200_0000000);
     }
 
-    pub fn set_admin(env: Env, new_admin: Address) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
-        env.storage().instance().set(&DataKey::Admin, &new_admin);
-    }
+    #[test]
+    fn test_rate_limits_queue_large_changes() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let xlm = token::Client::new(&env, &native_id);
+        let user = Address::generate(&env);
+        let guardian = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.set_protocol_fee_bps(&0);
+        client.grant_role(&Role::Guardian, &guardian);
+        client.deposit(&user, &100_0000000);
+        env.ledger().with_mut(|l| l.sequence_number += 1);
 
-    pub fn set_cooldown_period(env: Env, new_cooldown: u32) {
-        let admin = read_admin(&env);
-        admin.require_auth();
-        extend_instance(&env);
-        env.storage().instance().set(&DataKey::CooldownPeriod, &new_cooldown);
-        env.events().publish((soroban_sdk::symbol_short!("cd_upd"),), new_cooldown);
-    }
+        // 5% per call, 8% per 1000 ledgers, 50-ledger timelock.
+        client.set_rate_limits(&RateLimits {
+            per_call_bps: 500,
+            window_bps: 800,
+            window_ledgers: 1000,
+            delay_ledgers: 50,
+        });
 
-    // ==========================================================
-    // View functions
-    // ==========================================================
+        client.add_rewards_funded(&5_0000000);
+        assert_eq!(client.get_exchange_rate(), 1_0500000);
 
-    pub fn get_exchange_rate(env: Env) -> i128 {
-        extend_instance(&env);
-        let total_staked = read_i128(&env, &DataKey::TotalXlmStaked);
-        let total_supply = read_i128(&env, &DataKey::TotalSxlmSupply);
-        if total_supply == 0 {
-            RATE_PRECISION
-        } else {
-            total_staked * RATE_PRECISION / total_supply
-        }
-    }
+        // Within the per-call cap, but past the window cap: queued.
+        env.ledger().with_mut(|l| l.sequence_number += 10);
+        client.add_rewards_funded(&4_0000000);
+        assert_eq!(client.get_exchange_rate(), 1_0500000);
+        assert_eq!(client.get_pending_rate_change(&0).amount, 4_0000000);
 
-    pub fn total_xlm_staked(env: Env) -> i128 {
-        extend_instance(&env);
-        read_i128(&env, &DataKey::TotalXlmStaked)
-    }
+        // Over the per-call cap: queued, and deposits wait for it.
+        client.apply_slashing(&10_0000000);
+        assert_eq!(client.total_xlm_staked(), 105_0000000);
+        assert!(client.try_deposit(&user, &10_0000000).is_err());
 
-    pub fn total_sxlm_supply(env: Env) -> i128 {
-        extend_instance(&env);
-        read_i128(&env, &DataKey::TotalSxlmSupply)
-    }
+        assert!(client.try_execute_rate_change(&1).is_err());
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+        client.execute_rate_change(&1);
+        assert_eq!(client.total_xlm_staked(), 95_0000000);
+        assert!(client.try_get_pending_rate_change(&1).is_err());
+        client.deposit(&user, &10_0000000);
 
-    pub fn liquidity_buffer(env: Env) -> i128 {
-        extend_instance(&env);
-        read_i128(&env, &DataKey::LiquidityBuffer)
-    }
+        // The guardian vetoes the funded reward and the admin gets it back.
+        let admin_before = xlm.balance(&admin);
+        client.veto_rate_change(&0);
+        assert_eq!(xlm.balance(&admin), admin_before + 4_0000000);
+        assert!(client.try_execute_rate_change(&0).is_err());
 
-    pub fn treasury_balance(env: Env) -> i128 {
-        extend_instance(&env);
-        read_i128(&env, &DataKey::TreasuryBalance)
+        // Once the window has rolled past, the same reward goes straight in.
+        env.ledger().with_mut(|l| l.sequence_number += 1000);
+        client.add_rewards_funded(&4_0000000);
+        assert_eq!(client.total_xlm_staked(), 109_0000000);
     }
 
-    pub fn is_paused(env: Env) -> bool {
-        extend_instance(&env);
-        is_paused(&env)
-    }
+    #[test]
+    fn test_rate_limits_count_unvested_rewards() {
+        let env = Env::default();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.set_protocol_fee_bps(&0);
+        client.set_reward_vesting_period(&1000);
+        client.deposit(&user, &100_0000000);
+        env.ledger().with_mut(|l| l.sequence_number += 1);
 
-    pub fn protocol_fee_bps(env: Env) -> i128 {
-        extend_instance(&env);
-        PROTOCOL_FEE_BPS
-    }
+        // 1% per call, 5% per 10000 ledgers.
+        client.set_rate_limits(&RateLimits {
+            per_call_bps: 100,
+            window_bps: 500,
+            window_ledgers: 10000,
+            delay_ledgers: 50,
+        });
 
-    pub fn get_cooldown_period(env: Env) -> u32 {
-        extend_instance(&env);
-        read_cooldown(&env)
-    }
+        // Twenty 1% rewards: only the first five fit the window while the
+        // earlier ones are still vesting.
+        for _ in 0..20 {
+            client.add_rewards_funded(&1_0000000);
+            env.ledger().with_mut(|l| l.sequence_number += 1);
+        }
+        assert_eq!(client.total_xlm_staked() + client.unvested_rewards(), 105_0000000);
+        assert_eq!(client.get_pending_rate_change(&14).amount, 1_0000000);
 
-    pub fn get_withdrawal(env: Env, withdrawal_id: u64) -> WithdrawalRequest {
-        extend_instance(&env);
-        let queue = get_withdrawal_queue(&env);
-        queue.get(withdrawal_id).expect("withdrawal not found")
+        env.ledger().with_mut(|l| l.sequence_number += 1000);
+        assert_eq!(client.get_exchange_rate(), 1_0500000);
     }
 
-    pub fn get_validators(env: Env) -> Vec<Address> {
-        extend_instance(&env);
-        env.storage()
-            .instance()
-            .get(&DataKey::Validators)
-            .unwrap_or(Vec::new(&env))
-    }
+    #[test]
+    fn test_queued_unfunded_reward_needs_funding_rule_off() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+        client.set_rate_limits(&RateLimits {
+            per_call_bps: 500,
+            window_bps: 0,
+            window_ledgers: 0,
+            delay_ledgers: 50,
+        });
 
-    pub fn admin(env: Env) -> Address {
-        extend_instance(&env);
-        read_admin(&env)
+        client.add_rewards(&10_0000000);
+        client.set_require_funded_rewards(&true);
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+        assert!(client.try_execute_rate_change(&0).is_err());
+        client.veto_rate_change(&0);
+        assert_eq!(client.total_xlm_staked(), 100_0000000);
     }
-}
 
-use soroban_sdk::contractclient;
+    #[test]
+    fn test_queued_slash_holds_exits_until_applied() {
+        let env = Env::default();
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let validator = Address::generate(&env);
+        fund(&env, &native_id, &alice, 1000_0000000);
+        fund(&env, &native_id, &bob, 1000_0000000);
+        client.deposit(&alice, &100_0000000);
+        client.deposit(&bob, &100_0000000);
+        client.add_validator(&validator, &0, &10_000);
+        client.set_validator_allocation(&validator, &200_0000000);
+        client.set_rate_limits(&RateLimits {
+            per_call_bps: 500,
+            window_bps: 0,
+            window_ledgers: 0,
+            delay_ledgers: 50,
+        });
 
-#[contractclient(name = "SxlmTokenClient")]
-pub trait SxlmTokenInterface {
-    fn mint(env: Env, to: Address, amount: i128);
-    fn burn(env: Env, from: Address, amount: i128);
-    fn balance(env: Env, id: Address) -> i128;
-    fn total_supply(env: Env) -> i128;
-}
+        // A 10% slash, twice the per-call cap, waits out the timelock.
+        client.slash_validator(&validator, &20_0000000);
+        assert_eq!(client.get_exchange_rate(), RATE_PRECISION);
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use soroban_sdk::testutils::Address as _;
-    use soroban_sdk::Env;
+        // Bob cannot get out at the pre-slash rate in the meantime.
+        assert!(client.try_request_withdrawal(&bob, &100_0000000).is_err());
+        env.ledger().with_mut(|l| l.sequence_number += 50);
+        assert!(client.try_request_withdrawal(&bob, &100_0000000).is_err());
 
-    fn setup_staking(env: &Env) -> (StakingContractClient<'_>, Address, Address, Address) {
-        let contract_id = env.register_contract(None, StakingContract);
-        let client = StakingContractClient::new(env, &contract_id);
-        let admin = Address::generate(env);
-        let sxlm_token = Address::generate(env);
-        let native_token = Address::generate(env);
+        client.execute_rate_change(&0);
+        assert_eq!(client.get_exchange_rate(), 9000000);
+        assert_eq!(client.get_validator(&validator).allocated, 180_0000000);
 
-        client.initialize(&admin, &sxlm_token, &native_token, &17280u32);
-        (client, admin, sxlm_token, native_token)
+        // Bob exits after the slash and shares the loss.
+        client.request_withdrawal(&bob, &100_0000000);
+        assert_eq!(client.get_withdrawal(&0).xlm_amount, 90_0000000);
+
+        // A vetoed slash releases exits too.
+        client.apply_slashing(&20_0000000);
+        assert!(client.try_request_withdrawal(&alice, &10_0000000).is_err());
+        client.veto_rate_change(&1);
+        client.request_withdrawal(&alice, &10_0000000);
+        assert_eq!(client.get_exchange_rate(), 9000000);
     }
 
     #[test]
-    fn test_exchange_rate_initial() {
+    fn test_roles_default_to_admin_and_can_be_granted() {
         let env = Env::default();
-        let (client, _, _, _) = setup_staking(&env);
-        assert_eq!(client.get_exchange_rate(), RATE_PRECISION);
-        assert_eq!(client.total_xlm_staked(), 0);
-        assert_eq!(client.total_sxlm_supply(), 0);
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let keeper = Address::generate(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+
+        assert_eq!(client.role_holder(&Role::Rewarder), admin);
+        client.grant_role(&Role::Rewarder, &keeper);
+        assert!(client.has_role(&Role::Rewarder, &keeper));
+        assert!(!client.has_role(&Role::Upgrader, &keeper));
+
+        // Rewards are now authorized by the keeper, not the admin.
+        client.add_rewards(&10_0000000);
+        assert_eq!(env.auths()[0].0, keeper);
+
+        client.pause();
+        assert_eq!(env.auths()[0].0, admin);
+        client.unpause();
+
+        client.revoke_role(&Role::Rewarder);
+        assert_eq!(client.role_holder(&Role::Rewarder), admin);
+        client.add_rewards(&10_0000000);
+        assert_eq!(env.auths()[0].0, admin);
     }
 
     #[test]
-    fn test_view_functions() {
+    fn test_admin_handover_moves_ungranted_roles() {
         let env = Env::default();
-        env.mock_all_auths();
-        let (client, admin, _, _) = setup_staking(&env);
-        assert_eq!(client.liquidity_buffer(), 0);
+        let (client, admin, _, _) = setup_with_tokens(&env);
+        let new_admin = Address::generate(&env);
+        let guardian = Address::generate(&env);
+        client.grant_role(&Role::Guardian, &guardian);
+
+        client.propose_admin(&new_admin);
         assert_eq!(client.admin(), admin);
-        assert_eq!(client.get_validators().len(), 0);
-        assert_eq!(client.is_paused(), false);
-        assert_eq!(client.treasury_balance(), 0);
-        assert_eq!(client.protocol_fee_bps(), PROTOCOL_FEE_BPS);
+        client.accept_admin();
+        assert_eq!(env.auths()[0].0, new_admin);
+        assert_eq!(client.admin(), new_admin);
+
+        // Roles that were never granted follow the new admin; granted ones stay.
+        assert_eq!(client.role_holder(&Role::Upgrader), new_admin);
+        assert_eq!(client.role_holder(&Role::Guardian), guardian);
+        client.pause();
+        assert_eq!(env.auths()[0].0, guardian);
+        client.unpause();
+        assert_eq!(env.auths()[0].0, new_admin);
     }
 
     #[test]
-    #[should_panic(expected = "already initialized")]
-    fn test_double_initialize_panics() {
+    fn test_upgrader_schedules_and_holders_exit_in_the_delay() {
         let env = Env::default();
-        let (client, admin, sxlm, native) = setup_staking(&env);
-        client.initialize(&admin, &sxlm, &native, &100u32);
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let xlm = token::Client::new(&env, &native_id);
+        let upgrader = Address::generate(&env);
+        let user = Address::generate(&env);
+        let hash = BytesN::from_array(&env, &[7; 32]);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &100_0000000);
+        client.grant_role(&Role::Upgrader, &upgrader);
+
+        let eta = env.ledger().sequence() + upgrade::MIN_UPGRADE_DELAY;
+        client.schedule_upgrade(&hash, &eta);
+        assert_eq!(env.auths()[0].0, upgrader);
+        assert!(client.try_execute_upgrade().is_err());
+
+        // The withdrawal cooldown fits well inside the upgrade delay.
+        client.request_withdrawal(&user, &(100_0000000 - LOCKED_SXLM));
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        client.claim_withdrawal(&user, &0);
+        assert_eq!(xlm.balance(&user), 1000_0000000 - LOCKED_SXLM);
+        assert!(env.ledger().sequence() < eta);
+
+        client.cancel_upgrade();
+        assert_eq!(env.auths()[0].0, upgrader);
     }
 
     #[test]
-    fn test_add_rewards_with_fee() {
+    fn test_migrate_from_unversioned_layout() {
         let env = Env::default();
-        env.mock_all_auths();
-        let (client, _, _, _) = setup_staking(&env);
-        let gross_reward: i128 = 1000_0000000;
-        client.add_rewards(&gross_reward);
-        assert_eq!(client.total_xlm_staked(), 900_0000000);
-        assert_eq!(client.treasury_balance(), 100_0000000);
+        let (client, _, _, native_id) = setup_with_tokens(&env);
+        let user = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        client.deposit(&user, &500_0000000);
+
+        // Storage as written before layouts were versioned: no version, no
+        // stored fee, no rate history and every request in one queue map.
+        let legacy_count = MAX_PAGE_SIZE as u64 + 5;
+        env.as_contract(&client.address, || {
+            let mut legacy: Map<u64, WithdrawalRequest> = Map::new(&env);
+            for id in 0..legacy_count {
+                legacy.set(
+                    id,
+                    WithdrawalRequest {
+                        id,
+                        user: user.clone(),
+                        xlm_amount: 1_0000000,
+                        unlock_ledger: 100,
+                        claimed: false,
+                    },
+                );
+            }
+            env.storage().persistent().set(&DataKey::WithdrawalQueue, &legacy);
+            env.storage().persistent().remove(&DataKey::RateHistory);
+            env.storage().instance().set(&DataKey::WithdrawalCounter, &legacy_count);
+            env.storage().instance().remove(&DataKey::SchemaVersion);
+            env.storage().instance().remove(&DataKey::ProtocolFeeBps);
+        });
+        assert_eq!(client.version(), 0);
+        assert!(client.try_migrate(&SCHEMA_VERSION).is_err());
+
+        // The first call drains one batch and stays on version 0.
+        assert_eq!(client.migrate(&0), 0);
+        assert_eq!(client.migrate(&0), SCHEMA_VERSION);
+        assert_eq!(client.version(), SCHEMA_VERSION);
+        assert!(client.try_migrate(&0).is_err());
+
+        assert_eq!(client.pending_withdrawal_xlm(), legacy_count as i128 * 1_0000000);
+        assert_eq!(client.total_xlm_staked(), 500_0000000 - legacy_count as i128 * 1_0000000);
+        assert_eq!(client.get_user_withdrawal_count(&user), legacy_count as u32);
+        assert_eq!(client.protocol_fee_bps(), DEFAULT_PROTOCOL_FEE_BPS);
+        assert_eq!(client.get_rate_history().len(), 1);
+        assert_eq!(client.health_check().surplus, 0);
     }
 
     #[test]
-    fn test_withdraw_fees_partial() {
+    fn test_pause_single_operations() {
         let env = Env::default();
-        env.mock_all_auths();
-
-        let admin = Address::generate(&env);
-        let sxlm_id = env.register_stellar_asset_contract_v2(Address::generate(&env)).address();
-        let native_id = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let (client, admin, _, native_id) = setup_with_tokens(&env);
+        let xlm = token::Client::new(&env, &native_id);
+        let user = Address::generate(&env);
+        let guardian = Address::generate(&env);
+        fund(&env, &native_id, &user, 1000_0000000);
+        fund(&env, &native_id, &admin, 1000_0000000);
+        client.grant_role(&Role::Guardian, &guardian);
+        client.deposit(&user, &100_0000000);
+        client.add_liquidity(&50_0000000);
 
-        let contract_id = env.register_contract(None, StakingContract);
-        let client = StakingContractClient::new(&env, &contract_id);
-        client.initialize(&admin, &sxlm_id, &native_id, &17280u32);
+        // Stop deposits, keep exits open.
+        client.pause_operation(&Operation::Deposit);
+        assert_eq!(env.auths()[0].0, guardian);
+        assert!(client.is_operation_paused(&Operation::Deposit));
+        assert!(client.try_deposit(&user, &10_0000000).is_err());
+        client.request_withdrawal(&user, &10_0000000);
+        assert_eq!(xlm.balance(&user), 910_0000000);
 
-        // Fund contract with XLM so withdraw_fees can transfer out
-        let sac_client = soroban_sdk::token::StellarAssetClient::new(&env, &native_id);
-        sac_client.mint(&contract_id, &10_000_0000000);
+        // With instant exits paused, withdrawals fall back to the queue.
+        client.pause_operation(&Operation::InstantWithdrawal);
+        assert_eq!(client.max_instant_withdrawal(), 0);
+        assert!(!client.preview_withdrawal(&10_0000000).instant);
+        client.request_withdrawal(&user, &10_0000000);
+        assert_eq!(client.pending_withdrawal_xlm(), 10_0000000);
 
-        // add_rewards builds treasury (10% of 1000 = 100 treasury)
-        client.add_rewards(&1000_0000000);
-        assert_eq!(client.treasury_balance(), 100_0000000);
+        client.pause_operation(&Operation::DelayedWithdrawal);
+        assert!(client.try_request_withdrawal(&user, &10_0000000).is_err());
 
-        let token_client = soroban_sdk::token::Client::new(&env, &native_id);
-        let admin_balance_before = token_client.balance(&admin);
+        // Cancelling re-mints sXLM, so it is paused along with deposits.
+        assert!(client.try_cancel_withdrawal(&user, &0).is_err());
 
-        // Withdraw partial (50 XLM)
-        client.withdraw_fees(&50_0000000);
-        assert_eq!(client.treasury_balance(), 50_0000000);
-        assert_eq!(token_client.balance(&admin) - admin_balance_before, 50_0000000);
+        client.pause_operation(&Operation::Claim);
+        env.ledger().with_mut(|l| l.sequence_number += 100);
+        assert!(client.try_claim_withdrawal(&user, &0).is_err());
+        assert!(client.try_process_withdrawal_queue(&10).is_err());
+        client.pause_operation(&Operation::Rewards);
+        assert!(client.try_add_rewards(&1_0000000).is_err());
 
-        // Withdraw remaining (pass 0 = withdraw all)
-        client.withdraw_fees(&0);
-        assert_eq!(client.treasury_balance(), 0);
-    }
+        // Only the admin brings operations back.
+        client.unpause_operation(&Operation::Claim);
+        assert_eq!(env.auths()[0].0, admin);
+        client.claim_withdrawal(&user, &0);
+        assert_eq!(xlm.balance(&user), 920_0000000);
 
-    #[test]
-    fn test_pause_and_unpause() {
-        let env = Env::default();
-        env.mock_all_auths();
-        let (client, _, _, _) = setup_staking(&env);
-        assert_eq!(client.is_paused(), false);
+        // The global pause still covers deposits and withdrawals only.
+        client.unpause_operation(&Operation::Deposit);
         client.pause();
-        assert_eq!(client.is_paused(), true);
+        assert!(client.is_operation_paused(&Operation::Deposit));
+        assert!(!client.is_operation_paused(&Operation::Claim));
+        assert_eq!(client.process_withdrawal_queue(&10), 0);
         client.unpause();
-        assert_eq!(client.is_paused(), false);
+        assert_eq!(env.auths()[0].0, admin);
+        assert!(!client.is_operation_paused(&Operation::Deposit));
     }
 }
@@ -4,6 +4,8 @@ use soroban_sdk::{
     contract, contractimpl, contracttype, Address, BytesN, Env, String,
 };
 use soroban_token_sdk::TokenUtils;
+use sxlm_common::handover;
+use sxlm_common::upgrade::{self, PendingUpgrade};
 
 // ---------- TTL constants ----------
 // Testnet: ~5s per ledger
@@ -14,10 +16,15 @@ const INSTANCE_BUMP_AMOUNT: u32 = 518_400;        // bump to ~30 days
 const BALANCE_LIFETIME_THRESHOLD: u32 = 518_400;  // ~30 days — extend persistent if below this
 const BALANCE_BUMP_AMOUNT: u32 = 3_110_400;       // bump to ~180 days
 
+/// Storage layout written by `initialize` and reached by `migrate`.
+/// Deployments from before layouts were versioned read as version 0.
+const SCHEMA_VERSION: u32 = 1;
+
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
     Admin,
+    SchemaVersion,
     Minter,
     Allowance(AllowanceKey),
     Balance(Address),
@@ -49,6 +56,13 @@ fn extend_balance(env: &Env, addr: &Address) {
         .extend_ttl(&key, BALANCE_LIFETIME_THRESHOLD, BALANCE_BUMP_AMOUNT);
 }
 
+fn read_schema_version(env: &Env) -> u32 {
+    env.storage()
+        .instance()
+        .get(&DataKey::SchemaVersion)
+        .unwrap_or(0)
+}
+
 fn read_admin(env: &Env) -> Address {
     env.storage().instance().get(&DataKey::Admin).unwrap()
 }
@@ -141,14 +155,70 @@ impl SxlmToken {
         env.storage().instance().set(&DataKey::Name, &name);
         env.storage().instance().set(&DataKey::Symbol, &symbol);
         write_total_supply(&env, 0);
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
         extend_instance(&env);
     }
 
-    /// Upgrade the contract WASM. Only callable by admin.
-    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>) {
+    /// Announce a new token WASM, installable from `eta_ledger`. Balances and
+    /// allowances carry over; holders get at least
+    /// `upgrade::MIN_UPGRADE_DELAY` ledgers' notice.
+    pub fn schedule_upgrade(env: Env, new_wasm_hash: BytesN<32>, eta_ledger: u32) {
         let admin = read_admin(&env);
         admin.require_auth();
-        env.deployer().update_current_contract_wasm(new_wasm_hash);
+        extend_instance(&env);
+        upgrade::schedule(&env, new_wasm_hash, eta_ledger);
+    }
+
+    /// Install the scheduled upgrade once its eta has passed. Callable by anyone.
+    pub fn execute_upgrade(env: Env) {
+        upgrade::execute(&env);
+    }
+
+    pub fn cancel_upgrade(env: Env) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        upgrade::cancel(&env);
+    }
+
+    pub fn pending_upgrade(env: Env) -> Option<PendingUpgrade> {
+        extend_instance(&env);
+        upgrade::pending(&env)
+    }
+
+    /// Bring storage written by an older WASM up to `SCHEMA_VERSION`.
+    /// `from_version` must match `version()`, so a migration cannot be applied
+    /// twice or out of order. Returns the version reached.
+    pub fn migrate(env: Env, from_version: u32) -> u32 {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        let version = read_schema_version(&env);
+        if from_version != version {
+            panic!("schema version mismatch");
+        }
+        if version >= SCHEMA_VERSION {
+            panic!("already at latest schema version");
+        }
+
+        // Balances and allowances written before versioning already match
+        // version 1.
+        env.storage()
+            .instance()
+            .set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        env.events().publish(
+            (soroban_sdk::symbol_short!("migrated"),),
+            (from_version, SCHEMA_VERSION),
+        );
+        SCHEMA_VERSION
+    }
+
+    /// Storage layout version of this deployment.
+    pub fn version(env: Env) -> u32 {
+        extend_instance(&env);
+        read_schema_version(&env)
     }
 
     /// Bump instance TTL — can be called by anyone to keep contract alive.
@@ -277,14 +347,34 @@ impl SxlmToken {
         env.storage().instance().set(&DataKey::Minter, &new_minter);
     }
 
-    /// Transfer admin role.
-    pub fn set_admin(env: Env, new_admin: Address) {
+    /// Propose `new_admin` as the token admin, who sets the minter and
+    /// schedules upgrades. Takes effect once they call `accept_admin`.
+    pub fn propose_admin(env: Env, new_admin: Address) {
         let admin = read_admin(&env);
         admin.require_auth();
         extend_instance(&env);
+        handover::propose(&env, admin, new_admin);
+    }
+
+    /// Complete the handover. Only the proposed admin can call this.
+    pub fn accept_admin(env: Env) {
+        extend_instance(&env);
+        let new_admin = handover::accept(&env, read_admin(&env));
         env.storage().instance().set(&DataKey::Admin, &new_admin);
     }
 
+    pub fn cancel_admin_transfer(env: Env) {
+        let admin = read_admin(&env);
+        admin.require_auth();
+        extend_instance(&env);
+        handover::cancel(&env);
+    }
+
+    pub fn pending_admin(env: Env) -> Option<Address> {
+        extend_instance(&env);
+        handover::pending(&env)
+    }
+
     pub fn minter(env: Env) -> Address {
         extend_instance(&env);
         read_minter(&env)
@@ -423,4 +513,48 @@ mod test {
         client.set_minter(&new_minter);
         assert_eq!(client.minter(), new_minter);
     }
+
+    #[test]
+    fn test_admin_not_minter_controls_minter_and_upgrades() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, admin, _) = setup_token(&env);
+        let new_minter = Address::generate(&env);
+        let hash = BytesN::from_array(&env, &[7; 32]);
+
+        client.set_minter(&new_minter);
+        assert_eq!(env.auths()[0].0, admin);
+        client.schedule_upgrade(&hash, &(env.ledger().sequence() + upgrade::MIN_UPGRADE_DELAY));
+        assert_eq!(env.auths()[0].0, admin);
+    }
+
+    #[test]
+    fn test_migrate_from_unversioned_layout() {
+        let env = Env::default();
+        env.mock_all_auths();
+        let (client, admin, _) = setup_token(&env);
+        let holder = Address::generate(&env);
+        let spender = Address::generate(&env);
+        client.mint(&holder, &1000);
+        client.approve(&holder, &spender, &300, &1000);
+        // Storage as written before layouts were versioned.
+        env.as_contract(&client.address, || {
+            env.storage().instance().remove(&DataKey::SchemaVersion);
+        });
+        assert_eq!(client.version(), 0);
+        assert!(client.try_migrate(&1).is_err());
+
+        assert_eq!(client.migrate(&0), SCHEMA_VERSION);
+        assert_eq!(client.version(), SCHEMA_VERSION);
+        assert_eq!(client.admin(), admin);
+        assert_eq!(client.symbol(), String::from_str(&env, "sXLM"));
+        assert!(client.try_migrate(&SCHEMA_VERSION).is_err());
+
+        // Balances and allowances carry over.
+        assert_eq!(client.balance(&holder), 1000);
+        assert_eq!(client.total_supply(), 1000);
+        client.transfer_from(&spender, &holder, &spender, &300);
+        assert_eq!(client.balance(&spender), 300);
+        assert_eq!(client.allowance(&holder, &spender), 0);
+    }
 }